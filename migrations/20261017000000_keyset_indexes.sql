@@ -0,0 +1,3 @@
+-- Indexes backing keyset pagination when sorting by units or count
+CREATE INDEX IF NOT EXISTS idx_runepool_units_units_start ON runepool_unit_intervals (units, start_time);
+CREATE INDEX IF NOT EXISTS idx_runepool_units_count_start ON runepool_unit_intervals (count, start_time);
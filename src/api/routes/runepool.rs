@@ -1,9 +1,11 @@
-use crate::services::handlers::{
-    leveldb::get_runepool_units_history_from_leveldb,
-    mongodb::get_runepool_units_history_from_mongodb,
-    postgres::get_runepool_units_history_from_postgres,
-    rocksdb::get_runepool_units_history_from_rocksdb,
-    surrealdb::get_runepool_units_history_from_surrealdb,
+use crate::config::connect::{DB, LEVEL_DB, MONGO_CLIENT, PG_POOL, ROCKS_DB};
+use crate::services::handlers::runepool::{
+    get_runepool_units_deltas, get_runepool_units_gaps, get_runepool_units_history,
+    get_runepool_units_stats,
+};
+use crate::services::repository::{
+    leveldb::LevelStore, mongodb::MongoStore, postgres::PostgresStore, rocksdb::RocksStore,
+    surrealdb::SurrealStore, RunepoolStore,
 };
 use axum::{routing::get, Router};
 use http::Method;
@@ -11,6 +13,24 @@ use std::net::SocketAddr;
 use tokio::net::TcpListener;
 use tower_http::cors::{Any, CorsLayer};
 
+// Routes for a single backend, nested under /runepool/{backend}
+pub fn runepool_routes<S: RunepoolStore>(store: Option<S>) -> Router {
+    match store {
+        Some(store) => Router::new()
+            .route("/", get(get_runepool_units_history::<S>))
+            .route("/stats", get(get_runepool_units_stats::<S>))
+            .route("/deltas", get(get_runepool_units_deltas::<S>))
+            .route("/gaps", get(get_runepool_units_gaps::<S>))
+            .with_state(store),
+        None => {
+            tracing::warn!(
+                "{} not initialized, its routes are disabled",
+                S::DATABASE_TYPE.name()
+            );
+            Router::new()
+        }
+    }
+}
 
 pub async fn start_server() {
     let app = Router::new()
@@ -20,25 +40,25 @@ pub async fn start_server() {
             Method::POST,
             Method::DELETE,
         ]))
-        .route(
+        .nest(
             "/runepool/surrealdb",
-            get(get_runepool_units_history_from_surrealdb),
+            runepool_routes(Some(SurrealStore::new(DB.clone()))),
         )
-        .route(
+        .nest(
             "/runepool/postgres",
-            get(get_runepool_units_history_from_postgres),
+            runepool_routes(PG_POOL.get().cloned().map(PostgresStore::new)),
         )
-        .route(
+        .nest(
             "/runepool/mongo",
-            get(get_runepool_units_history_from_mongodb),
+            runepool_routes(MONGO_CLIENT.get().cloned().map(MongoStore::new)),
         )
-        .route(
+        .nest(
             "/runepool/rocks",
-            get(get_runepool_units_history_from_rocksdb),
+            runepool_routes(ROCKS_DB.get().cloned().map(RocksStore::new)),
         )
-        .route(
+        .nest(
             "/runepool/level",
-            get(get_runepool_units_history_from_leveldb),
+            runepool_routes(LEVEL_DB.get().cloned().map(LevelStore::new)),
         );
 
     let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
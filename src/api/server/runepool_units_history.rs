@@ -9,9 +9,6 @@ use reqwest::Client;
 
 pub async fn fetch_initial_runepool_units_history(
 ) -> Result<RunepoolUnitsHistoryResponse, reqwest::Error> {
-    let client = Client::new();
-    let base_url = get_midgard_api_url();
-
     let params = RunepoolUnitsHistoryParams {
         interval: Some(Interval::Hour),
         count: Some(400),
@@ -19,6 +16,16 @@ pub async fn fetch_initial_runepool_units_history(
         to: None,
     };
 
+    fetch_runepool_units_history(&get_midgard_api_url(), &params).await
+}
+
+// One /history/runepool request against the Midgard instance at base_url
+pub async fn fetch_runepool_units_history(
+    base_url: &str,
+    params: &RunepoolUnitsHistoryParams,
+) -> Result<RunepoolUnitsHistoryResponse, reqwest::Error> {
+    let client = Client::new();
+
     let mut url = reqwest::Url::parse(&format!("{}/history/runepool", base_url))
         .expect("Failed to parse URL");
 
@@ -18,6 +18,8 @@ use surrealdb::{
 };
 use tracing::{error, info};
 
+use crate::services::repository::rocksdb::UNITS_INDEX_CF;
+
 pub static DB: Lazy<Surreal<Client>> = Lazy::new(Surreal::init);
 pub static PG_POOL: OnceCell<PgPool> = OnceCell::new();
 pub static ROCKS_DB: OnceCell<Arc<rocksdb::DB>> = OnceCell::new();
@@ -70,8 +72,7 @@ pub async fn connect_mongodb(url: &str) -> mongodb::error::Result<()> {
     // Store static MONGO_CLIENT look above
     if let Err(_e) = MONGO_CLIENT.set(client) {
         error!("Failed to set MongoDB client");
-        return Err(mongodb::error::Error::from(std::io::Error::new(
-            std::io::ErrorKind::Other,
+        return Err(mongodb::error::Error::from(std::io::Error::other(
             "Failed to initialize MongoDB client",
         )));
     }
@@ -80,11 +81,17 @@ pub async fn connect_mongodb(url: &str) -> mongodb::error::Result<()> {
     Ok(())
 }
 
-pub async fn connect_rocksdb(url: &str) -> std::result::Result<(), anyhow::Error> {
+// Opens the database with the column families the store expects
+pub fn open_rocksdb(url: &str) -> std::result::Result<rocksdb::DB, rocksdb::Error> {
     let mut options = rocksdb::Options::default();
     options.create_if_missing(true);
+    options.create_missing_column_families(true);
 
-    match rocksdb::DB::open(&options, url) {
+    rocksdb::DB::open_cf(&options, url, [UNITS_INDEX_CF])
+}
+
+pub async fn connect_rocksdb(url: &str) -> std::result::Result<(), anyhow::Error> {
+    match open_rocksdb(url) {
         Ok(db) => {
             if let Err(_e) = ROCKS_DB.set(Arc::new(db)) {
                 error!("Failed to set RocksDB instance");
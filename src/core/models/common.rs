@@ -1,12 +1,15 @@
-use chrono::{DateTime, NaiveDateTime, Utc};
+use chrono::{DateTime, Datelike, Days, Months, NaiveDate, NaiveTime, TimeDelta, Utc};
 use serde::{Deserialize, Serialize};
 
-use super::runepool_units_history::RunepoolUnitsHistoryQueryParams;
+use super::runepool_units_history::{RunepoolUnitsHistoryQueryParams, RunepoolUnitsInterval};
+use std::cmp::Ordering;
 
 pub const DEFAULT_PAGE_SIZE: u32 = 30;
 pub const MAX_PAGE_SIZE: u32 = 400;
+pub const MAX_POINTS: u32 = 5000;
+pub const MAX_SMOOTHING_WINDOW: u32 = 1000;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
 pub enum Interval {
     #[serde(rename = "5min")]
@@ -34,6 +37,73 @@ impl std::fmt::Display for Interval {
     }
 }
 
+impl Interval {
+    // Start of the bucket holding the time, in UTC. Weeks start on monday like
+    // postgres date_trunc and mongo $dateTrunc with startOfWeek monday
+    pub fn bucket_start(&self, time: DateTime<Utc>) -> DateTime<Utc> {
+        let fixed = |seconds: i64| {
+            let timestamp = time.timestamp();
+            DateTime::from_timestamp(timestamp - timestamp.rem_euclid(seconds), 0).unwrap_or(time)
+        };
+        let date = time.date_naive();
+        let first_of = |month: u32| {
+            NaiveDate::from_ymd_opt(date.year(), month, 1)
+                .unwrap_or(date)
+                .and_time(NaiveTime::MIN)
+                .and_utc()
+        };
+
+        match self {
+            Interval::FiveMin => fixed(5 * 60),
+            Interval::Hour => fixed(60 * 60),
+            Interval::Day => fixed(24 * 60 * 60),
+            Interval::Week => (date - Days::new(date.weekday().num_days_from_monday() as u64))
+                .and_time(NaiveTime::MIN)
+                .and_utc(),
+            Interval::Month => first_of(date.month()),
+            Interval::Quarter => first_of((date.month() - 1) / 3 * 3 + 1),
+            Interval::Year => first_of(1),
+        }
+    }
+
+    // Start of the next bucket
+    pub fn bucket_end(&self, bucket_start: DateTime<Utc>) -> DateTime<Utc> {
+        let end = match self {
+            Interval::FiveMin => bucket_start.checked_add_signed(TimeDelta::minutes(5)),
+            Interval::Hour => bucket_start.checked_add_signed(TimeDelta::hours(1)),
+            Interval::Day => bucket_start.checked_add_days(Days::new(1)),
+            Interval::Week => bucket_start.checked_add_days(Days::new(7)),
+            Interval::Month => bucket_start.checked_add_months(Months::new(1)),
+            Interval::Quarter => bucket_start.checked_add_months(Months::new(3)),
+            Interval::Year => bucket_start.checked_add_months(Months::new(12)),
+        };
+        end.unwrap_or(bucket_start)
+    }
+}
+
+// How the counts of the intervals in a bucket are combined when resampling. Count
+// is the number of members at the end of an interval, so by default a bucket keeps
+// the last one like units
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CountMode {
+    #[default]
+    Last,
+    Sum,
+}
+
+// What goes into the slots missing between stored intervals: nothing, nulls, the
+// interval before the gap or a straight line between the intervals on both sides
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Fill {
+    #[default]
+    None,
+    Null,
+    Previous,
+    Linear,
+}
+
 impl TryFrom<String> for Interval {
     type Error = String;
 
@@ -51,41 +121,333 @@ impl TryFrom<String> for Interval {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortField {
+    StartTime,
+    Units,
+    Count,
+}
+
+impl SortField {
+    pub fn column(&self) -> &'static str {
+        match self {
+            SortField::StartTime => "start_time",
+            SortField::Units => "units",
+            SortField::Count => "count",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+impl SortOrder {
+    pub fn as_sql(&self) -> &'static str {
+        match self {
+            SortOrder::Asc => "ASC",
+            SortOrder::Desc => "DESC",
+        }
+    }
+}
+
+impl SortOrder {
+    fn from_sql(order: &str) -> Option<Self> {
+        match order {
+            "ASC" => Some(SortOrder::Asc),
+            "DESC" => Some(SortOrder::Desc),
+            _ => None,
+        }
+    }
+}
+
+impl SortField {
+    fn from_column(column: &str) -> Option<Self> {
+        match column {
+            "start_time" => Some(SortField::StartTime),
+            "units" => Some(SortField::Units),
+            "count" => Some(SortField::Count),
+            _ => None,
+        }
+    }
+}
+
+// Position right after the last interval of a page for keyset pagination: the sort
+// value of that interval plus its start time as the tie breaker. It remembers the
+// sort it was made for since it means nothing under another ordering
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HistoryCursor {
+    pub sort_field: SortField,
+    pub sort_order: SortOrder,
+    // Units or count of the last interval, 0 when sorting by time
+    pub value: u64,
+    pub start_time: DateTime<Utc>,
+}
+
+impl HistoryCursor {
+    pub fn after(
+        interval: &RunepoolUnitsInterval,
+        sort_field: SortField,
+        sort_order: SortOrder,
+    ) -> Self {
+        let value = match sort_field {
+            SortField::StartTime => 0,
+            SortField::Units => interval.units,
+            SortField::Count => interval.count,
+        };
+        Self {
+            sort_field,
+            sort_order,
+            value,
+            start_time: interval.start_time,
+        }
+    }
+
+    // True when the interval sorts after the cursor, ties on the value go by start time ascending
+    pub fn precedes(&self, interval: &RunepoolUnitsInterval) -> bool {
+        let value = match self.sort_field {
+            SortField::StartTime => {
+                return match self.sort_order {
+                    SortOrder::Asc => interval.start_time > self.start_time,
+                    SortOrder::Desc => interval.start_time < self.start_time,
+                }
+            }
+            SortField::Units => interval.units,
+            SortField::Count => interval.count,
+        };
+
+        match (value.cmp(&self.value), self.sort_order) {
+            (Ordering::Equal, _) => interval.start_time > self.start_time,
+            (Ordering::Greater, SortOrder::Asc) | (Ordering::Less, SortOrder::Desc) => true,
+            _ => false,
+        }
+    }
+
+    // Opaque to clients, hex so it goes in a query string as is
+    pub fn encode(&self) -> String {
+        format!(
+            "{}:{}:{}:{}",
+            self.sort_field.column(),
+            self.sort_order.as_sql(),
+            self.value,
+            self.start_time.timestamp()
+        )
+        .bytes()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+    }
+
+    pub fn decode(cursor: &str) -> Result<Self, String> {
+        let invalid = || "Invalid cursor".to_string();
+
+        let bytes = (0..cursor.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(cursor.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(invalid)?;
+        let decoded = String::from_utf8(bytes).map_err(|_| invalid())?;
+
+        let parts: Vec<&str> = decoded.split(':').collect();
+        let [field, order, value, start_time] = parts.as_slice() else {
+            return Err(invalid());
+        };
+
+        Ok(Self {
+            sort_field: SortField::from_column(field).ok_or_else(invalid)?,
+            sort_order: SortOrder::from_sql(order).ok_or_else(invalid)?,
+            value: value.parse().map_err(|_| invalid())?,
+            start_time: DateTime::from_timestamp(start_time.parse().map_err(|_| invalid())?, 0)
+                .ok_or_else(invalid)?,
+        })
+    }
+}
+
+// Inclusive bounds on units or count, every comparison the API takes (gt, lte,
+// between...) narrows one of these. min > max matches nothing
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ValueRange {
+    pub min: Option<u64>,
+    pub max: Option<u64>,
+}
+
+impl ValueRange {
+    const EMPTY: Self = Self {
+        min: Some(1),
+        max: Some(0),
+    };
+
+    pub fn at_least(self, value: u64) -> Self {
+        Self {
+            min: Some(self.min.map_or(value, |min| min.max(value))),
+            ..self
+        }
+    }
+
+    pub fn at_most(self, value: u64) -> Self {
+        Self {
+            max: Some(self.max.map_or(value, |max| max.min(value))),
+            ..self
+        }
+    }
+
+    pub fn greater_than(self, value: u64) -> Self {
+        match value.checked_add(1) {
+            Some(min) => self.at_least(min),
+            None => Self::EMPTY,
+        }
+    }
+
+    pub fn less_than(self, value: u64) -> Self {
+        match value.checked_sub(1) {
+            Some(max) => self.at_most(max),
+            None => Self::EMPTY,
+        }
+    }
+
+    pub fn is_bounded(&self) -> bool {
+        self.min.is_some() || self.max.is_some()
+    }
+
+    pub fn contains(&self, value: u64) -> bool {
+        self.min.is_none_or(|min| value >= min) && self.max.is_none_or(|max| value <= max)
+    }
+}
+
 impl RunepoolUnitsHistoryQueryParams {
-    pub fn get_sort_field(&self) -> &str {
+    pub fn get_sort_field(&self) -> SortField {
         match self.sort_by.as_deref() {
-            Some("units") => "units",
-            Some("count") => "count",
-            Some("timestamp") => "start_time",
-            _ => "start_time",
-        }
-    }
-
-    pub fn parse_date_range(&self) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
-        parse_date_range(&self.date_range)
-    }
-}
-
-fn parse_date_range(date_range: &Option<String>) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
-    date_range.as_ref().and_then(|range| {
-        let parts: Vec<&str> = range.split(',').collect();
-        if parts.len() == 2 {
-            let start = NaiveDateTime::parse_from_str(
-                &format!("{} 00:00:00", parts[0]),
-                "%Y-%m-%d %H:%M:%S",
-            )
-            .ok()?;
-            let end = NaiveDateTime::parse_from_str(
-                &format!("{} 23:59:59", parts[1]),
-                "%Y-%m-%d %H:%M:%S",
-            )
-            .ok()?;
-            Some((
-                DateTime::from_naive_utc_and_offset(start, Utc),
-                DateTime::from_naive_utc_and_offset(end, Utc),
-            ))
+            Some("units") => SortField::Units,
+            Some("count") => SortField::Count,
+            Some("timestamp") => SortField::StartTime,
+            _ => SortField::StartTime,
+        }
+    }
+
+    pub fn get_sort_order(&self) -> SortOrder {
+        if self.order.as_deref() == Some("desc") {
+            SortOrder::Desc
         } else {
-            None
+            SortOrder::Asc
+        }
+    }
+
+    // [from, to) from either `from`/`to` or the older `date_range`, an interval is
+    // inside when it starts at or after `from` and ends by `to`
+    pub fn parse_time_range(&self) -> Result<TimeRange, String> {
+        let (from, to) = match (&self.date_range, &self.from, &self.to) {
+            (Some(_), Some(_), _) | (Some(_), _, Some(_)) => {
+                return Err("date_range can't be combined with from/to".to_string())
+            }
+            (Some(date_range), None, None) => parse_date_range(date_range)
+                .map(|(from, to)| (Some(from), Some(to)))
+                .ok_or_else(|| format!("Invalid date_range: {}", date_range))?,
+            (None, from, to) => (
+                from.as_deref().map(parse_time_bound).transpose()?,
+                to.as_deref().map(parse_time_bound).transpose()?,
+            ),
+        };
+
+        if let (Some(from), Some(to)) = (from, to) {
+            if from > to {
+                return Err("from must not be after to".to_string());
+            }
+        }
+        Ok((from, to))
+    }
+
+    // units_gt/gte/lt/lte and units_between (inclusive "min,max") all narrow one range
+    pub fn parse_units_range(&self) -> Result<ValueRange, String> {
+        let range = value_range(self.units_gt, self.units_gte, self.units_lt, self.units_lte);
+        match self.units_between.as_deref() {
+            Some(between) => {
+                let (min, max) = parse_between(between)
+                    .ok_or_else(|| format!("Invalid units_between: {}", between))?;
+                Ok(range.at_least(min).at_most(max))
+            }
+            None => Ok(range),
+        }
+    }
+
+    pub fn count_range(&self) -> ValueRange {
+        value_range(self.count_gt, self.count_gte, self.count_lt, self.count_lte)
+    }
+
+    // The cursor has to come from a page with the same sort as this request
+    pub fn parse_cursor(&self) -> Result<Option<HistoryCursor>, String> {
+        let Some(cursor) = self.cursor.as_deref() else {
+            return Ok(None);
+        };
+
+        let cursor = HistoryCursor::decode(cursor)?;
+        if cursor.sort_field != self.get_sort_field() || cursor.sort_order != self.get_sort_order()
+        {
+            return Err("Cursor was issued for a different sort_by/order".to_string());
+        }
+        Ok(Some(cursor))
+    }
+}
+
+fn value_range(gt: Option<u64>, gte: Option<u64>, lt: Option<u64>, lte: Option<u64>) -> ValueRange {
+    let mut range = ValueRange::default();
+    if let Some(value) = gt {
+        range = range.greater_than(value);
+    }
+    if let Some(value) = gte {
+        range = range.at_least(value);
+    }
+    if let Some(value) = lt {
+        range = range.less_than(value);
+    }
+    if let Some(value) = lte {
+        range = range.at_most(value);
+    }
+    range
+}
+
+fn parse_between(between: &str) -> Option<(u64, u64)> {
+    let (min, max) = between.split_once(',')?;
+    let (min, max) = (min.trim().parse().ok()?, max.trim().parse().ok()?);
+    (min <= max).then_some((min, max))
+}
+
+pub type TimeRange = (Option<DateTime<Utc>>, Option<DateTime<Utc>>);
+
+// Unix seconds take at least 9 digits (1973-03-03 on), so a bare year like 2024 or a
+// compact date like 20240102 is rejected rather than read as a time in 1970
+const MIN_TIMESTAMP_DIGITS: usize = 9;
+
+// RFC 3339, unix seconds or a date (midnight UTC)
+fn parse_time_bound(value: &str) -> Result<DateTime<Utc>, String> {
+    let invalid = || format!("Invalid time: {}", value);
+
+    if let Ok(timestamp) = value.parse::<i64>() {
+        if value.trim_start_matches(['-', '+']).len() < MIN_TIMESTAMP_DIGITS {
+            return Err(format!(
+                "Ambiguous time: {}, unix seconds take at least {} digits",
+                value, MIN_TIMESTAMP_DIGITS
+            ));
         }
-    })
+        return DateTime::from_timestamp(timestamp, 0).ok_or_else(invalid);
+    }
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map(|date| date.and_time(NaiveTime::MIN).and_utc())
+        .map_err(|_| invalid())
+}
+
+// "YYYY-MM-DD,YYYY-MM-DD" covers both days, so it ends at midnight after the second one
+fn parse_date_range(date_range: &str) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    let (start, end) = date_range.split_once(',')?;
+    let start = NaiveDate::parse_from_str(start, "%Y-%m-%d").ok()?;
+    let end = NaiveDate::parse_from_str(end, "%Y-%m-%d")
+        .ok()?
+        .succ_opt()?;
+    Some((
+        start.and_time(NaiveTime::MIN).and_utc(),
+        end.and_time(NaiveTime::MIN).and_utc(),
+    ))
 }
@@ -1,7 +1,7 @@
 use chrono::{DateTime, TimeZone, Utc};
 use serde::{Deserialize, Serialize};
 
-use super::common::Interval;
+use super::common::{CountMode, Fill, Interval};
 
 mod timestamp_serialization {
     use super::*;
@@ -49,6 +49,30 @@ mod u64_serialization {
     }
 }
 
+mod optional_u64_serialization {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S>(value: &Option<u64>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match value {
+            Some(value) => super::u64_serialization::serialize(value, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Option::<String>::deserialize(deserializer)?
+            .map(|value| value.trim().replace(",", "").parse::<u64>())
+            .transpose()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct RunepoolUnitsInterval {
     #[serde(rename = "count", with = "u64_serialization")]
@@ -61,7 +85,7 @@ pub struct RunepoolUnitsInterval {
     pub units: u64,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct MetaStats {
     #[serde(rename = "endCount", with = "u64_serialization")]
     pub end_count: u64,
@@ -77,11 +101,282 @@ pub struct MetaStats {
     pub start_units: u64,
 }
 
+impl MetaStats {
+    // Same as Midgard's meta: where the earliest interval starts and the latest one ends
+    pub fn new(first: &RunepoolUnitsInterval, last: &RunepoolUnitsInterval) -> Self {
+        Self {
+            start_time: first.start_time,
+            end_time: last.end_time,
+            start_count: first.count,
+            end_count: last.count,
+            start_units: first.units,
+            end_units: last.units,
+        }
+    }
+}
+
+// A history interval with the moving averages asked for with sma/ema
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SmoothedRunepoolUnitsInterval {
+    #[serde(flatten)]
+    pub interval: RunepoolUnitsInterval,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub units_sma: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub units_ema: Option<f64>,
+}
+
+impl From<RunepoolUnitsInterval> for SmoothedRunepoolUnitsInterval {
+    fn from(interval: RunepoolUnitsInterval) -> Self {
+        Self {
+            interval,
+            units_sma: None,
+            units_ema: None,
+        }
+    }
+}
+
+// A slot of the history grid asked for with fill, either a stored interval or one made
+// up for a missing slot. Made up slots have null count and units with fill=null
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FilledRunepoolUnitsInterval {
+    #[serde(rename = "count", with = "optional_u64_serialization")]
+    pub count: Option<u64>,
+    #[serde(rename = "endTime", with = "timestamp_serialization")]
+    pub end_time: DateTime<Utc>,
+    #[serde(rename = "startTime", with = "timestamp_serialization")]
+    pub start_time: DateTime<Utc>,
+    #[serde(rename = "units", with = "optional_u64_serialization")]
+    pub units: Option<u64>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub filled: bool,
+}
+
+impl From<RunepoolUnitsInterval> for FilledRunepoolUnitsInterval {
+    fn from(interval: RunepoolUnitsInterval) -> Self {
+        Self {
+            count: Some(interval.count),
+            end_time: interval.end_time,
+            start_time: interval.start_time,
+            units: Some(interval.units),
+            filled: false,
+        }
+    }
+}
+
+// Midgard's response, also what the history endpoints return with their own intervals
 #[derive(Debug, Serialize, Deserialize)]
-pub struct RunepoolUnitsHistoryResponse {
-    pub intervals: Vec<RunepoolUnitsInterval>,
+#[serde(rename_all = "camelCase")]
+pub struct RunepoolUnitsHistoryResponse<I = RunepoolUnitsInterval> {
+    pub intervals: Vec<I>,
     #[serde(rename = "meta")]
     pub meta_stats: MetaStats,
+    // Pass back as `cursor` for the next page, null on the last page
+    pub next_cursor: Option<String>,
+    // Only with include_total=true
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pagination: Option<PaginationMeta>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PaginationMeta {
+    // Intervals matching the filters across all pages
+    pub total: u64,
+    // Null when paging with a cursor
+    pub page: Option<u32>,
+    pub limit: u32,
+    pub has_more: bool,
+    pub prev: Option<String>,
+    pub next: Option<String>,
+}
+
+// Summary of one field over the intervals in range, first and last go by start time
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FieldStats {
+    pub min: u64,
+    pub max: u64,
+    pub avg: f64,
+    // Sample standard deviation, 0 for a single interval
+    pub stddev: f64,
+    pub first: u64,
+    pub last: u64,
+    // last - first, saturating at the i64 bounds
+    pub change: i64,
+    // Null when the first value is 0
+    pub change_pct: Option<f64>,
+}
+
+// Absolute and percentage change from `from` to `to`, no percentage from 0. Worked out
+// in i128 so values past i64::MAX don't wrap, the absolute change saturates instead
+fn change(from: u64, to: u64) -> (i64, Option<f64>) {
+    let change = i128::from(to) - i128::from(from);
+    let clamped = i64::try_from(change).unwrap_or(if change < 0 { i64::MIN } else { i64::MAX });
+    (
+        clamped,
+        (from != 0).then(|| change as f64 / from as f64 * 100.0),
+    )
+}
+
+impl FieldStats {
+    pub fn new(min: u64, max: u64, avg: f64, stddev: f64, first: u64, last: u64) -> Self {
+        let (change, change_pct) = change(first, last);
+        Self {
+            min,
+            max,
+            avg,
+            stddev,
+            first,
+            last,
+            change,
+            change_pct,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RunepoolUnitsStats {
+    // Start of the first and end of the last interval in range
+    #[serde(with = "timestamp_serialization")]
+    pub start_time: DateTime<Utc>,
+    #[serde(with = "timestamp_serialization")]
+    pub end_time: DateTime<Utc>,
+    pub intervals: u64,
+    pub units: FieldStats,
+    pub count: FieldStats,
+}
+
+// A stored interval with its change against the interval stored right before it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RunepoolUnitsDelta {
+    #[serde(flatten)]
+    pub interval: RunepoolUnitsInterval,
+    // All null for the first interval stored, the percentages also when the
+    // previous value is 0
+    pub units_change: Option<i64>,
+    pub units_change_pct: Option<f64>,
+    pub count_change: Option<i64>,
+    pub count_change_pct: Option<f64>,
+}
+
+impl RunepoolUnitsDelta {
+    pub fn new(
+        interval: RunepoolUnitsInterval,
+        previous_units: Option<u64>,
+        previous_count: Option<u64>,
+    ) -> Self {
+        let units = previous_units.map(|previous| change(previous, interval.units));
+        let count = previous_count.map(|previous| change(previous, interval.count));
+        Self {
+            interval,
+            units_change: units.map(|(change, _)| change),
+            units_change_pct: units.and_then(|(_, pct)| pct),
+            count_change: count.map(|(change, _)| change),
+            count_change_pct: count.and_then(|(_, pct)| pct),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RunepoolUnitsDeltasResponse {
+    pub intervals: Vec<RunepoolUnitsDelta>,
+    pub next_cursor: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pagination: Option<PaginationMeta>,
+}
+
+// Two intervals next to each other in time order whose ends don't meet: the later one
+// starts after the earlier one ends, before it ends or at the same time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IntervalBreak {
+    pub previous_start: DateTime<Utc>,
+    pub previous_end: DateTime<Utc>,
+    pub start_time: DateTime<Utc>,
+    pub end_time: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TimeSpan {
+    #[serde(with = "timestamp_serialization")]
+    pub start_time: DateTime<Utc>,
+    #[serde(with = "timestamp_serialization")]
+    pub end_time: DateTime<Utc>,
+    pub seconds: i64,
+}
+
+impl TimeSpan {
+    pub fn new(start_time: DateTime<Utc>, end_time: DateTime<Utc>) -> Self {
+        Self {
+            start_time,
+            end_time,
+            seconds: (end_time - start_time).num_seconds(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RunepoolUnitsGaps {
+    // Start of the first and end of the last interval in range
+    #[serde(with = "timestamp_serialization")]
+    pub start_time: DateTime<Utc>,
+    #[serde(with = "timestamp_serialization")]
+    pub end_time: DateTime<Utc>,
+    pub intervals: u64,
+    // From the end of an interval to the start of the next one
+    pub gaps: Vec<TimeSpan>,
+    // The time an interval shares with the one before it
+    pub overlaps: Vec<TimeSpan>,
+    // Same as overlaps, for intervals starting at the same time as the one before them
+    pub duplicates: Vec<TimeSpan>,
+    pub missing_seconds: i64,
+    // Share of start_time..end_time outside the gaps
+    pub coverage_pct: f64,
+}
+
+impl RunepoolUnitsGaps {
+    // `breaks` are in time order
+    pub fn new(
+        start_time: DateTime<Utc>,
+        end_time: DateTime<Utc>,
+        intervals: u64,
+        breaks: impl IntoIterator<Item = IntervalBreak>,
+    ) -> Self {
+        let (mut gaps, mut overlaps, mut duplicates) = (Vec::new(), Vec::new(), Vec::new());
+        for gap in breaks {
+            let shared = TimeSpan::new(gap.start_time, gap.end_time.min(gap.previous_end));
+            if gap.start_time == gap.previous_start {
+                duplicates.push(shared);
+            } else if gap.start_time < gap.previous_end {
+                overlaps.push(shared);
+            } else if gap.start_time > gap.previous_end {
+                gaps.push(TimeSpan::new(gap.previous_end, gap.start_time));
+            }
+        }
+
+        let missing_seconds = gaps.iter().map(|gap| gap.seconds).sum();
+        let seconds = (end_time - start_time).num_seconds();
+        let coverage_pct = match seconds {
+            0 => 100.0,
+            seconds => (seconds - missing_seconds) as f64 / seconds as f64 * 100.0,
+        };
+        Self {
+            start_time,
+            end_time,
+            intervals,
+            gaps,
+            overlaps,
+            duplicates,
+            missing_seconds,
+            coverage_pct,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -95,9 +390,33 @@ pub struct RunepoolUnitsHistoryParams {
 #[derive(Debug, Deserialize)]
 pub struct RunepoolUnitsHistoryQueryParams {
     pub date_range: Option<String>,
+    // RFC 3339, unix seconds or YYYY-MM-DD, either one can be left out
+    pub from: Option<String>,
+    pub to: Option<String>,
     pub page: Option<u32>,
     pub limit: Option<u32>,
     pub sort_by: Option<String>,
     pub order: Option<String>,
     pub units_gt: Option<u64>,
+    pub units_gte: Option<u64>,
+    pub units_lt: Option<u64>,
+    pub units_lte: Option<u64>,
+    // "min,max", both ends included
+    pub units_between: Option<String>,
+    pub count_gt: Option<u64>,
+    pub count_gte: Option<u64>,
+    pub count_lt: Option<u64>,
+    pub count_lte: Option<u64>,
+    pub cursor: Option<String>,
+    pub include_total: Option<bool>,
+    // Aggregates the stored intervals into buckets of this size
+    pub interval: Option<Interval>,
+    pub count_mode: Option<CountMode>,
+    // Moving averages of the units over this many intervals (or buckets)
+    pub sma: Option<u32>,
+    pub ema: Option<u32>,
+    // Downsamples the whole range to this many intervals instead of returning a page
+    pub points: Option<u32>,
+    // Adds the slots missing between stored intervals, flagged as filled
+    pub fill: Option<Fill>,
 }
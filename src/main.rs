@@ -6,7 +6,7 @@ use db_tester::{
         },
         tracing::setup_tracing,
     },
-    services::client::get_midgard_api_url,
+    services::{client::get_midgard_api_url, repository::runepool::prepare_stores},
 };
 use dotenv::dotenv;
 
@@ -46,6 +46,8 @@ async fn main() {
         tracing::error!("Failed to initialize LevelDB: {}", e);
     }
 
+    prepare_stores().await;
+
     if let Err(e) = fetch_and_store_initial_data().await {
         tracing::error!("Failed to fetch and store initial data: {}", e);
     }
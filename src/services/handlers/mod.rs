@@ -1,5 +1 @@
-pub mod leveldb;
-pub mod mongodb;
-pub mod postgres;
-pub mod rocksdb;
-pub mod surrealdb;
+pub mod runepool;
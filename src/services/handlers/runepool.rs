@@ -0,0 +1,472 @@
+use crate::core::models::common::{
+    Fill, HistoryCursor, SortField, SortOrder, ValueRange, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE,
+    MAX_POINTS, MAX_SMOOTHING_WINDOW,
+};
+use crate::core::models::runepool_units_history::{
+    FilledRunepoolUnitsInterval, MetaStats, PaginationMeta, RunepoolUnitsDeltasResponse,
+    RunepoolUnitsHistoryQueryParams, RunepoolUnitsHistoryResponse, SmoothedRunepoolUnitsInterval,
+};
+use crate::services::repository::downsampling::downsample;
+use crate::services::repository::filling::{fill, TooManySlots};
+use crate::services::repository::smoothing::{smooth, Smoothing};
+use crate::services::repository::{HistoryQuery, Resample, RunepoolStore};
+use axum::extract::{OriginalUri, State};
+use axum::http::{StatusCode, Uri};
+use axum::response::Response;
+use axum::{extract::Query, response::IntoResponse, Json};
+use serde_json::json;
+
+fn database_error(e: anyhow::Error) -> Response {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!({
+            "success": false,
+            "error": format!("Database error: {}", e)
+        })),
+    )
+        .into_response()
+}
+
+fn bad_request(error: String) -> Response {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(json!({
+            "success": false,
+            "error": error
+        })),
+    )
+        .into_response()
+}
+
+// The same request with its page or cursor replaced
+fn page_link(uri: &Uri, param: &str, value: &str) -> String {
+    let query = uri.query().unwrap_or_default();
+    let pairs = url::form_urlencoded::parse(query.as_bytes())
+        .filter(|(key, _)| key != "page" && key != "cursor");
+
+    let mut serializer = url::form_urlencoded::Serializer::new(String::new());
+    serializer.extend_pairs(pairs);
+    serializer.append_pair(param, value);
+    format!("{}?{}", uri.path(), serializer.finish())
+}
+
+// Links to the neighbouring pages, by cursor when the request paged with one
+fn pagination(
+    uri: &Uri,
+    total: u64,
+    by_cursor: bool,
+    page: u32,
+    limit: u32,
+    has_more: bool,
+    next_cursor: Option<&str>,
+) -> PaginationMeta {
+    if by_cursor {
+        PaginationMeta {
+            total,
+            page: None,
+            limit,
+            has_more,
+            prev: None,
+            next: next_cursor.map(|cursor| page_link(uri, "cursor", cursor)),
+        }
+    } else {
+        PaginationMeta {
+            total,
+            page: Some(page),
+            limit,
+            has_more,
+            prev: (page > 0).then(|| page_link(uri, "page", &(page - 1).to_string())),
+            next: has_more.then(|| page_link(uri, "page", &(page + 1).to_string())),
+        }
+    }
+}
+
+// Meta of resampled history, from the earliest and the latest bucket
+async fn bucket_meta<S: RunepoolStore>(
+    store: &S,
+    query: &HistoryQuery,
+    resample: Resample,
+) -> anyhow::Result<Option<MetaStats>> {
+    let edge = |sort_order| HistoryQuery {
+        limit: 1,
+        offset: 0,
+        sort_field: SortField::StartTime,
+        sort_order,
+        ..query.clone()
+    };
+    let (first, last) = (edge(SortOrder::Asc), edge(SortOrder::Desc));
+    let (first, last) = tokio::try_join!(
+        store.resample(&first, resample),
+        store.resample(&last, resample)
+    )?;
+
+    Ok(match (first.first(), last.first()) {
+        (Some(first), Some(last)) => Some(MetaStats::new(first, last)),
+        _ => None,
+    })
+}
+
+pub async fn get_runepool_units_history<S: RunepoolStore>(
+    State(store): State<S>,
+    OriginalUri(uri): OriginalUri,
+    Query(params): Query<RunepoolUnitsHistoryQueryParams>,
+) -> impl IntoResponse {
+    let limit = params.limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+
+    let after = match params.parse_cursor() {
+        Ok(after) => after,
+        Err(e) => return bad_request(e),
+    };
+
+    let (start_time, end_time) = match params.parse_time_range() {
+        Ok(time_range) => time_range,
+        Err(e) => return bad_request(e),
+    };
+
+    let units = match params.parse_units_range() {
+        Ok(units) => units,
+        Err(e) => return bad_request(e),
+    };
+
+    let resample = params.interval.map(|interval| Resample {
+        interval,
+        count_mode: params.count_mode.unwrap_or_default(),
+    });
+    let include_total = params.include_total.unwrap_or(false);
+    // Buckets are paged by page/limit only
+    if resample.is_some() && (after.is_some() || include_total) {
+        return bad_request("cursor and include_total can't be used with interval".to_string());
+    }
+
+    // A cursor already marks where the page starts, page only applies without one
+    let page = params.page.unwrap_or(0);
+    let offset = match after {
+        Some(_) => 0,
+        None => page * limit,
+    };
+
+    let sort_field = params.get_sort_field();
+    let sort_order = params.get_sort_order();
+
+    let smoothing = Smoothing {
+        sma: params.sma,
+        ema: params.ema,
+    };
+    let window_range = 1..=MAX_SMOOTHING_WINDOW;
+    if [params.sma, params.ema]
+        .into_iter()
+        .flatten()
+        .any(|window| !window_range.contains(&window))
+    {
+        return bad_request(format!(
+            "sma and ema need a window between 1 and {}",
+            MAX_SMOOTHING_WINDOW
+        ));
+    }
+    // The averages run over the series in time order
+    if smoothing.is_requested() && sort_field != SortField::StartTime {
+        return bad_request("sma and ema need the history sorted by timestamp".to_string());
+    }
+
+    // The downsampled series covers the whole range in one response
+    let points = params.points;
+    if let Some(points) = points {
+        if !(3..=MAX_POINTS).contains(&points) {
+            return bad_request(format!("points needs to be between 3 and {}", MAX_POINTS));
+        }
+        if after.is_some() || include_total || resample.is_some() || smoothing.is_requested() {
+            return bad_request(
+                "points can't be used with cursor, include_total, interval, sma or ema".to_string(),
+            );
+        }
+        if sort_field != SortField::StartTime {
+            return bad_request("points needs the history sorted by timestamp".to_string());
+        }
+    }
+
+    // Missing slots are the ones nothing is stored for, so the series can't be filtered
+    let fill_mode = params.fill.unwrap_or_default();
+    if fill_mode != Fill::None {
+        if sort_field != SortField::StartTime {
+            return bad_request("fill needs the history sorted by timestamp".to_string());
+        }
+        if points.is_some() || smoothing.is_requested() {
+            return bad_request("fill can't be used with points, sma or ema".to_string());
+        }
+        if units.is_bounded() || params.count_range().is_bounded() {
+            return bad_request("fill can't be used with units or count filters".to_string());
+        }
+    }
+
+    let query = HistoryQuery {
+        limit,
+        offset,
+        start_time,
+        end_time,
+        units,
+        count: params.count_range(),
+        sort_field,
+        sort_order,
+        after,
+    };
+
+    // The page, the total (a separate native count) and the meta of the whole filtered
+    // range all run at once
+    let intervals = async {
+        match (points, resample) {
+            (Some(points), _) => downsample(&store, &query, points).await,
+            (None, Some(resample)) => store.resample(&query, resample).await,
+            (None, None) => store.query(&query).await,
+        }
+    };
+    let total = async {
+        if include_total {
+            store.count(&query).await.map(Some)
+        } else {
+            Ok(None)
+        }
+    };
+    let meta = async {
+        match resample {
+            Some(resample) => bucket_meta(&store, &query, resample).await,
+            None => store.meta(&query).await,
+        }
+    };
+    let (intervals, total, meta_stats) = match tokio::join!(intervals, total, meta) {
+        (Ok(intervals), Ok(total), Ok(meta_stats)) => (intervals, total, meta_stats),
+        (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => return database_error(e),
+    };
+
+    let Some(meta_stats) = meta_stats.filter(|_| !intervals.is_empty()) else {
+        return Json(json!({
+            "success": true,
+            "data": "no data found in the database for the given params"
+        }))
+        .into_response();
+    };
+
+    let has_more = match (total, after) {
+        _ if points.is_some() => false,
+        (Some(total), None) => offset as u64 + (intervals.len() as u64) < total,
+        // A full page means there can be more after it
+        _ => intervals.len() == limit as usize,
+    };
+
+    let next_cursor = intervals
+        .last()
+        .filter(|_| has_more && resample.is_none())
+        .map(|last| HistoryCursor::after(last, sort_field, sort_order).encode());
+
+    let pagination = total.map(|total| {
+        pagination(
+            &uri,
+            total,
+            after.is_some(),
+            page,
+            limit,
+            has_more,
+            next_cursor.as_deref(),
+        )
+    });
+
+    if fill_mode != Fill::None {
+        let intervals = match fill(&store, &query, resample, intervals, fill_mode).await {
+            Ok(intervals) => intervals,
+            Err(e) if e.is::<TooManySlots>() => return bad_request(e.to_string()),
+            Err(e) => return database_error(e),
+        };
+        return Json(
+            RunepoolUnitsHistoryResponse::<FilledRunepoolUnitsInterval> {
+                intervals,
+                meta_stats,
+                next_cursor,
+                pagination,
+            },
+        )
+        .into_response();
+    }
+
+    let intervals = if smoothing.is_requested() {
+        match smooth(&store, &query, resample, intervals, smoothing).await {
+            Ok(intervals) => intervals,
+            Err(e) => return database_error(e),
+        }
+    } else {
+        intervals.into_iter().map(Into::into).collect()
+    };
+
+    Json(
+        RunepoolUnitsHistoryResponse::<SmoothedRunepoolUnitsInterval> {
+            intervals,
+            meta_stats,
+            next_cursor,
+            pagination,
+        },
+    )
+    .into_response()
+}
+
+// Summary of units and count over the same filters the history takes, paging and
+// sorting params are ignored
+pub async fn get_runepool_units_stats<S: RunepoolStore>(
+    State(store): State<S>,
+    Query(params): Query<RunepoolUnitsHistoryQueryParams>,
+) -> impl IntoResponse {
+    let (start_time, end_time) = match params.parse_time_range() {
+        Ok(time_range) => time_range,
+        Err(e) => return bad_request(e),
+    };
+
+    let units = match params.parse_units_range() {
+        Ok(units) => units,
+        Err(e) => return bad_request(e),
+    };
+
+    let query = HistoryQuery {
+        limit: 0,
+        offset: 0,
+        start_time,
+        end_time,
+        units,
+        count: params.count_range(),
+        sort_field: SortField::StartTime,
+        sort_order: SortOrder::Asc,
+        after: None,
+    };
+
+    match store.stats(&query).await {
+        Ok(Some(stats)) => Json(stats).into_response(),
+        Ok(None) => Json(json!({
+            "success": true,
+            "data": "no data found in the database for the given params"
+        }))
+        .into_response(),
+        Err(e) => database_error(e),
+    }
+}
+
+// Where the stored intervals in the time range don't line up: gaps between neighbours,
+// overlaps and duplicate starts, with the share of the range they cover
+pub async fn get_runepool_units_gaps<S: RunepoolStore>(
+    State(store): State<S>,
+    Query(params): Query<RunepoolUnitsHistoryQueryParams>,
+) -> impl IntoResponse {
+    let (start_time, end_time) = match params.parse_time_range() {
+        Ok(time_range) => time_range,
+        Err(e) => return bad_request(e),
+    };
+
+    let query = HistoryQuery {
+        limit: 0,
+        offset: 0,
+        start_time,
+        end_time,
+        units: ValueRange::default(),
+        count: ValueRange::default(),
+        sort_field: SortField::StartTime,
+        sort_order: SortOrder::Asc,
+        after: None,
+    };
+
+    match store.gaps(&query).await {
+        Ok(Some(gaps)) => Json(gaps).into_response(),
+        Ok(None) => Json(json!({
+            "success": true,
+            "data": "no data found in the database for the given params"
+        }))
+        .into_response(),
+        Err(e) => database_error(e),
+    }
+}
+
+// Each interval in the time range with its change against the one stored before it.
+// Always sorted by time, paged like the history
+pub async fn get_runepool_units_deltas<S: RunepoolStore>(
+    State(store): State<S>,
+    OriginalUri(uri): OriginalUri,
+    Query(params): Query<RunepoolUnitsHistoryQueryParams>,
+) -> impl IntoResponse {
+    if params.get_sort_field() != SortField::StartTime {
+        return bad_request("deltas are sorted by timestamp only".to_string());
+    }
+
+    let limit = params.limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+
+    let after = match params.parse_cursor() {
+        Ok(after) => after,
+        Err(e) => return bad_request(e),
+    };
+
+    let (start_time, end_time) = match params.parse_time_range() {
+        Ok(time_range) => time_range,
+        Err(e) => return bad_request(e),
+    };
+
+    let page = params.page.unwrap_or(0);
+    let offset = match after {
+        Some(_) => 0,
+        None => page * limit,
+    };
+    let sort_order = params.get_sort_order();
+    let include_total = params.include_total.unwrap_or(false);
+
+    let query = HistoryQuery {
+        limit,
+        offset,
+        start_time,
+        end_time,
+        units: ValueRange::default(),
+        count: ValueRange::default(),
+        sort_field: SortField::StartTime,
+        sort_order,
+        after,
+    };
+
+    let total = async {
+        if include_total {
+            store.count(&query).await.map(Some)
+        } else {
+            Ok(None)
+        }
+    };
+    let (intervals, total) = match tokio::join!(store.deltas(&query), total) {
+        (Ok(intervals), Ok(total)) => (intervals, total),
+        (Err(e), _) | (_, Err(e)) => return database_error(e),
+    };
+
+    if intervals.is_empty() {
+        return Json(json!({
+            "success": true,
+            "data": "no data found in the database for the given params"
+        }))
+        .into_response();
+    }
+
+    let has_more = match (total, after) {
+        (Some(total), None) => offset as u64 + (intervals.len() as u64) < total,
+        _ => intervals.len() == limit as usize,
+    };
+
+    let next_cursor = intervals.last().filter(|_| has_more).map(|last| {
+        HistoryCursor::after(&last.interval, SortField::StartTime, sort_order).encode()
+    });
+
+    let pagination = total.map(|total| {
+        pagination(
+            &uri,
+            total,
+            after.is_some(),
+            page,
+            limit,
+            has_more,
+            next_cursor.as_deref(),
+        )
+    });
+
+    Json(RunepoolUnitsDeltasResponse {
+        intervals,
+        next_cursor,
+        pagination,
+    })
+    .into_response()
+}
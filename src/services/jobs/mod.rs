@@ -1,5 +0,0 @@
-pub mod get_level;
-pub mod get_mongo;
-pub mod get_postgres;
-pub mod get_rocks;
-pub mod get_surreal;
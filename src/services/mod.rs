@@ -1,4 +1,3 @@
 pub mod client;
 pub mod handlers;
-pub mod jobs;
 pub mod repository;
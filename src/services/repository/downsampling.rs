@@ -0,0 +1,176 @@
+use crate::core::models::common::{HistoryCursor, SortField, SortOrder};
+use crate::core::models::runepool_units_history::RunepoolUnitsInterval;
+use anyhow::{ensure, Result};
+
+use super::{HistoryQuery, RunepoolStore};
+
+// Intervals read per query while streaming the series
+const BATCH_SIZE: u32 = 1000;
+
+fn point(interval: &RunepoolUnitsInterval) -> (f64, f64) {
+    (
+        interval.start_time.timestamp() as f64,
+        interval.units as f64,
+    )
+}
+
+// Largest triangle three buckets over units by start time. The first and the last
+// interval are always kept and the ones in between are split into points - 2 buckets,
+// each one giving the interval that makes the largest triangle with the interval picked
+// before it and the average of the next bucket. Intervals come in one at a time in time
+// order and only two buckets are held at once
+struct Lttb {
+    points: u64,
+    // Intervals per bucket, from the length of the series counted up front
+    every: f64,
+    keep_all: bool,
+    seen: u64,
+    picked: Vec<RunepoolUnitsInterval>,
+    // Held back until the next interval shows it isn't the last one
+    held: Option<RunepoolUnitsInterval>,
+    bucket: u64,
+    filling: Vec<RunepoolUnitsInterval>,
+    // Complete, waiting for the average of the bucket after it
+    pending: Option<Vec<RunepoolUnitsInterval>>,
+}
+
+impl Lttb {
+    fn new(len: u64, points: u32) -> Self {
+        let points = points as u64;
+        Self {
+            points,
+            every: len.saturating_sub(2) as f64 / points.saturating_sub(2).max(1) as f64,
+            keep_all: len <= points,
+            seen: 0,
+            picked: Vec::new(),
+            held: None,
+            bucket: 0,
+            filling: Vec::new(),
+            pending: None,
+        }
+    }
+
+    // Index of the first interval in a bucket, the first interval of all is index 0
+    fn bucket_start(&self, bucket: u64) -> u64 {
+        (bucket as f64 * self.every).floor() as u64 + 1
+    }
+
+    fn push(&mut self, interval: RunepoolUnitsInterval) {
+        if self.keep_all {
+            self.picked.push(interval);
+            return;
+        }
+
+        let index = self.seen;
+        self.seen += 1;
+        let Some(previous) = self.held.replace(interval) else {
+            return;
+        };
+        if index == 1 {
+            self.picked.push(previous);
+            return;
+        }
+
+        // More intervals than counted all go into the last bucket
+        let previous_index = index - 1;
+        while self.bucket + 1 < self.points - 2
+            && previous_index >= self.bucket_start(self.bucket + 1)
+        {
+            let complete = std::mem::take(&mut self.filling);
+            self.complete(complete);
+            self.bucket += 1;
+        }
+        self.filling.push(previous);
+    }
+
+    fn complete(&mut self, bucket: Vec<RunepoolUnitsInterval>) {
+        if bucket.is_empty() {
+            return;
+        }
+        let count = bucket.len() as f64;
+        let (x, y) = bucket
+            .iter()
+            .map(point)
+            .fold((0.0, 0.0), |(x, y), (px, py)| (x + px, y + py));
+        if let Some(pending) = self.pending.replace(bucket) {
+            self.pick(pending, (x / count, y / count));
+        }
+    }
+
+    fn pick(&mut self, bucket: Vec<RunepoolUnitsInterval>, next: (f64, f64)) {
+        let Some(a) = self.picked.last().map(point) else {
+            return;
+        };
+        let area = |interval: &RunepoolUnitsInterval| {
+            let (x, y) = point(interval);
+            ((a.0 - next.0) * (y - a.1) - (a.0 - x) * (next.1 - a.1)).abs()
+        };
+        // The first of equally large triangles
+        let mut best: Option<(f64, RunepoolUnitsInterval)> = None;
+        for interval in bucket {
+            let area = area(&interval);
+            if best.as_ref().is_none_or(|(best, _)| area > *best) {
+                best = Some((area, interval));
+            }
+        }
+        self.picked.extend(best.map(|(_, interval)| interval));
+    }
+
+    fn finish(mut self) -> Vec<RunepoolUnitsInterval> {
+        let Some(last) = self.held.take() else {
+            return self.picked;
+        };
+        let filling = std::mem::take(&mut self.filling);
+        self.complete(filling);
+        if let Some(pending) = self.pending.take() {
+            self.pick(pending, point(&last));
+        }
+        self.picked.push(last);
+        self.picked
+    }
+}
+
+// The series matching the query downsampled to at most `points` intervals, read from the
+// store in time order one batch at a time. Sorting only decides the order of the result,
+// limit/offset and the cursor are ignored
+pub async fn downsample<S: RunepoolStore>(
+    store: &S,
+    query: &HistoryQuery,
+    points: u32,
+) -> Result<Vec<RunepoolUnitsInterval>> {
+    ensure!(points >= 3, "downsampling needs at least 3 points");
+
+    let mut series = HistoryQuery {
+        limit: BATCH_SIZE,
+        offset: 0,
+        sort_field: SortField::StartTime,
+        sort_order: SortOrder::Asc,
+        after: None,
+        ..query.clone()
+    };
+    let mut lttb = Lttb::new(store.count(&series).await?, points);
+    loop {
+        let batch = store.query(&series).await?;
+        let Some(last) = batch.last() else {
+            break;
+        };
+        series.after = Some(HistoryCursor::after(
+            last,
+            SortField::StartTime,
+            SortOrder::Asc,
+        ));
+        let full = batch.len() == BATCH_SIZE as usize;
+        for interval in batch {
+            lttb.push(interval);
+        }
+        if !full {
+            break;
+        }
+    }
+
+    let mut picked = lttb.finish();
+    if query.sort_order == SortOrder::Desc {
+        picked.reverse();
+    }
+    Ok(picked)
+}
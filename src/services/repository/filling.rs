@@ -0,0 +1,134 @@
+use crate::core::models::common::{Fill, SortField, SortOrder};
+use crate::core::models::runepool_units_history::{
+    FilledRunepoolUnitsInterval, RunepoolUnitsInterval,
+};
+use anyhow::{ensure, Result};
+use chrono::{DateTime, Utc};
+use std::fmt;
+
+use super::smoothing::before_page;
+use super::{HistoryQuery, Resample, RunepoolStore};
+
+// Most slots a page gets filled in with, a page of 5 minute buckets with years missing
+// between them would otherwise be millions
+pub const MAX_FILLED: usize = 10_000;
+
+#[derive(Debug)]
+pub struct TooManySlots;
+
+impl fmt::Display for TooManySlots {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "fill would add more than {} intervals, narrow the time range",
+            MAX_FILLED
+        )
+    }
+}
+
+impl std::error::Error for TooManySlots {}
+
+// Missing slots are as long as the interval before the gap, or a bucket when resampled
+fn slot_end(
+    previous: &RunepoolUnitsInterval,
+    resample: Option<Resample>,
+    start: DateTime<Utc>,
+) -> DateTime<Utc> {
+    match resample {
+        Some(resample) => resample.interval.bucket_end(start),
+        None => start + (previous.end_time - previous.start_time),
+    }
+}
+
+fn interpolate(from: u64, to: u64, fraction: f64) -> u64 {
+    (from as f64 + (to as f64 - from as f64) * fraction).round() as u64
+}
+
+// Adds the slots between two neighbouring intervals, the last one is cut short when the
+// gap isn't a whole number of slots
+fn fill_gap(
+    previous: &RunepoolUnitsInterval,
+    next: &RunepoolUnitsInterval,
+    resample: Option<Resample>,
+    fill: Fill,
+    filled: &mut Vec<FilledRunepoolUnitsInterval>,
+    slots: &mut usize,
+) -> Result<()> {
+    let span = (next.start_time - previous.start_time).num_seconds() as f64;
+    let mut start = previous.end_time;
+    while start < next.start_time {
+        let end = slot_end(previous, resample, start).min(next.start_time);
+        // Intervals without a length have no slots to step through
+        if end <= start {
+            break;
+        }
+        if *slots == MAX_FILLED {
+            return Err(TooManySlots.into());
+        }
+        *slots += 1;
+
+        let (count, units) = match fill {
+            Fill::None | Fill::Null => (None, None),
+            Fill::Previous => (Some(previous.count), Some(previous.units)),
+            Fill::Linear => {
+                let fraction = (start - previous.start_time).num_seconds() as f64 / span;
+                (
+                    Some(interpolate(previous.count, next.count, fraction)),
+                    Some(interpolate(previous.units, next.units, fraction)),
+                )
+            }
+        };
+        filled.push(FilledRunepoolUnitsInterval {
+            count,
+            end_time: end,
+            start_time: start,
+            units,
+            filled: true,
+        });
+        start = end;
+    }
+    Ok(())
+}
+
+// A page of history sorted by time with the slots missing between its intervals added.
+// The slots between the oldest interval on the page and the one stored before it are on
+// this page too, so the pages put together are one regular grid from the first interval
+// in range to the last
+pub async fn fill<S: RunepoolStore>(
+    store: &S,
+    query: &HistoryQuery,
+    resample: Option<Resample>,
+    page: Vec<RunepoolUnitsInterval>,
+    fill: Fill,
+) -> Result<Vec<FilledRunepoolUnitsInterval>> {
+    ensure!(
+        query.sort_field == SortField::StartTime,
+        "filling needs the history sorted by time"
+    );
+    if fill == Fill::None {
+        return Ok(page.into_iter().map(Into::into).collect());
+    }
+
+    let mut series = before_page(store, query, resample, &page, 1).await?;
+    let before_len = series.len();
+    match query.sort_order {
+        SortOrder::Asc => series.extend(page),
+        SortOrder::Desc => series.extend(page.into_iter().rev()),
+    }
+
+    let mut filled = Vec::new();
+    let mut slots = 0;
+    for (i, interval) in series.iter().enumerate() {
+        if let Some(previous) = i.checked_sub(1).map(|i| &series[i]) {
+            fill_gap(previous, interval, resample, fill, &mut filled, &mut slots)?;
+        }
+        if i >= before_len {
+            filled.push(interval.clone().into());
+        }
+    }
+
+    if query.sort_order == SortOrder::Desc {
+        filled.reverse();
+    }
+    Ok(filled)
+}
@@ -0,0 +1,679 @@
+use super::{HistoryQuery, Resample};
+use crate::core::models::common::{CountMode, SortField, SortOrder, ValueRange};
+use crate::core::models::runepool_units_history::{
+    FieldStats, RunepoolUnitsDelta, RunepoolUnitsInterval, RunepoolUnitsStats,
+};
+use chrono::{DateTime, Utc};
+use std::cmp::Ordering;
+
+// Key layout shared by RocksDB and LevelDB:
+// prefix ++ start (8 bytes big endian) ++ end (8 bytes big endian)
+// so byte order is the same as chronological order and range scans can seek
+pub const INTERVAL_KEY_PREFIX: &[u8] = b"runepool_units:";
+
+// Secondary index on units: prefix ++ units (8 bytes big endian) ++ start ++ end.
+// The value is the serialized interval as well, so index scans never go back to
+// the primary keys
+pub const UNITS_INDEX_PREFIX: &[u8] = b"runepool_units_by_units:";
+
+const TIMESTAMP_LEN: usize = 8;
+const UNITS_LEN: usize = 8;
+const INTERVAL_KEY_LEN: usize = INTERVAL_KEY_PREFIX.len() + 2 * TIMESTAMP_LEN;
+const UNITS_INDEX_KEY_LEN: usize = UNITS_INDEX_PREFIX.len() + UNITS_LEN + 2 * TIMESTAMP_LEN;
+
+// Flipping the sign bit keeps negative timestamps ordered before positive ones
+fn encode_timestamp(timestamp: i64) -> [u8; TIMESTAMP_LEN] {
+    ((timestamp as u64) ^ (1 << 63)).to_be_bytes()
+}
+
+fn decode_timestamp(bytes: &[u8]) -> Option<DateTime<Utc>> {
+    let encoded = u64::from_be_bytes(bytes.try_into().ok()?);
+    DateTime::from_timestamp((encoded ^ (1 << 63)) as i64, 0)
+}
+
+pub fn interval_key(start_time: DateTime<Utc>, end_time: DateTime<Utc>) -> Vec<u8> {
+    let mut key = Vec::with_capacity(INTERVAL_KEY_LEN);
+    key.extend_from_slice(INTERVAL_KEY_PREFIX);
+    key.extend_from_slice(&encode_timestamp(start_time.timestamp()));
+    key.extend_from_slice(&encode_timestamp(end_time.timestamp()));
+    key
+}
+
+// Start and end time of the interval under a primary key
+pub fn interval_key_times(key: &[u8]) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    let times = key.strip_prefix(INTERVAL_KEY_PREFIX)?;
+    let (start, end) = times.split_at_checked(TIMESTAMP_LEN)?;
+    Some((decode_timestamp(start)?, decode_timestamp(end)?))
+}
+
+pub fn key_for_interval(interval: &RunepoolUnitsInterval) -> Vec<u8> {
+    interval_key(interval.start_time, interval.end_time)
+}
+
+// [lower, upper) key range holding every interval that can fall inside the time range.
+// Keys sort by start time first, so the scan starts at `start` and stops past `end`
+// (an interval ending by `end` can't start after it), end times are checked by the caller
+fn interval_key_bounds(
+    start: Option<DateTime<Utc>>,
+    end: Option<DateTime<Utc>>,
+) -> (Vec<u8>, Vec<u8>) {
+    let lower = match start {
+        Some(start) => start_time_key(start.timestamp()),
+        None => INTERVAL_KEY_PREFIX.to_vec(),
+    };
+    let upper = match end {
+        Some(end) => start_time_key(end.timestamp().saturating_add(1)),
+        None => prefix_end(INTERVAL_KEY_PREFIX),
+    };
+    (lower, upper)
+}
+
+// Smallest key sorting after every key with the prefix
+fn prefix_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    if let Some(last) = end.last_mut() {
+        *last += 1;
+    }
+    end
+}
+
+fn start_time_key(timestamp: i64) -> Vec<u8> {
+    let mut key = Vec::with_capacity(INTERVAL_KEY_PREFIX.len() + TIMESTAMP_LEN);
+    key.extend_from_slice(INTERVAL_KEY_PREFIX);
+    key.extend_from_slice(&encode_timestamp(timestamp));
+    key
+}
+
+pub fn units_index_key(interval: &RunepoolUnitsInterval) -> Vec<u8> {
+    let mut key = Vec::with_capacity(UNITS_INDEX_KEY_LEN);
+    key.extend_from_slice(UNITS_INDEX_PREFIX);
+    key.extend_from_slice(&interval.units.to_be_bytes());
+    key.extend_from_slice(&encode_timestamp(interval.start_time.timestamp()));
+    key.extend_from_slice(&encode_timestamp(interval.end_time.timestamp()));
+    key
+}
+
+fn units_key(units: u64) -> Vec<u8> {
+    let mut key = UNITS_INDEX_PREFIX.to_vec();
+    key.extend_from_slice(&units.to_be_bytes());
+    key
+}
+
+// [lower, upper) index range holding every interval with units inside the range
+fn units_index_bounds(units: ValueRange) -> (Vec<u8>, Vec<u8>) {
+    let lower = match units.min {
+        Some(min) => units_key(min),
+        None => UNITS_INDEX_PREFIX.to_vec(),
+    };
+    let upper = match units.max.and_then(|max| max.checked_add(1)) {
+        Some(end) => units_key(end),
+        None => prefix_end(UNITS_INDEX_PREFIX),
+    };
+    // An empty units range gives an empty key range rather than an inverted one
+    let upper = upper.max(lower.clone());
+    (lower, upper)
+}
+
+// Which keyspace a query is read from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KvScan {
+    // Primary keys, seeked to the date range
+    Time,
+    // Units index, seeked to the units range
+    Units,
+}
+
+// Keys a scan walks, [lower, upper) in one keyspace, backwards when reverse is set
+#[derive(Debug, Clone)]
+pub struct KvRange {
+    pub scan: KvScan,
+    pub lower: Vec<u8>,
+    pub upper: Vec<u8>,
+    pub reverse: bool,
+}
+
+impl KvRange {
+    // Primary keys inside the time range, oldest first
+    pub fn time(start: Option<DateTime<Utc>>, end: Option<DateTime<Utc>>) -> Self {
+        let (lower, upper) = interval_key_bounds(start, end);
+        Self {
+            scan: KvScan::Time,
+            lower,
+            upper,
+            reverse: false,
+        }
+    }
+
+    // Units index entries inside the units range, lowest units first
+    pub fn units(units: ValueRange) -> Self {
+        let (lower, upper) = units_index_bounds(units);
+        Self {
+            scan: KvScan::Units,
+            lower,
+            upper,
+            reverse: false,
+        }
+    }
+
+    // The units index serves units ordering, and units filters that come without a
+    // date range to narrow the primary keys. Count filters have no index and are
+    // checked on every entry. Descending time order walks the primary keys backwards,
+    // every other order is sorted by the page. Pages in time order after a cursor
+    // always go by the primary keys, which pick up right where the last page ended
+    // while the units index would be read and sorted again from the start
+    pub fn for_query(query: &HistoryQuery) -> Self {
+        let time_cursor = query.sort_field == SortField::StartTime && query.after.is_some();
+        let units_filter_only =
+            query.units.is_bounded() && !query.has_time_filter() && !time_cursor;
+        if query.sort_field == SortField::Units || units_filter_only {
+            return Self::units(query.units);
+        }
+
+        Self {
+            reverse: query.sort_field == SortField::StartTime
+                && query.sort_order == SortOrder::Desc,
+            ..Self::time(query.start_time, query.end_time)
+        }
+    }
+
+    // Range of one page: the query's range, seeked past the cursor when the keys are
+    // in the cursor's order so deep pages start as fast as the first one
+    pub fn for_page(query: &HistoryQuery) -> Self {
+        let mut range = Self::for_query(query);
+        let Some(cursor) = query.after.filter(|_| range.is_ordered_for(query)) else {
+            return range;
+        };
+
+        let next_start = cursor.start_time.timestamp().saturating_add(1);
+        match (range.scan, query.sort_order) {
+            (KvScan::Time, SortOrder::Asc) => {
+                range.lower = range.lower.max(start_time_key(next_start));
+            }
+            (KvScan::Time, SortOrder::Desc) => {
+                range.upper = range
+                    .upper
+                    .min(start_time_key(cursor.start_time.timestamp()));
+            }
+            (KvScan::Units, _) => {
+                let mut key = units_key(cursor.value);
+                key.extend_from_slice(&encode_timestamp(next_start));
+                range.lower = range.lower.max(key);
+            }
+        }
+        // The cursor can sit past the end of the range
+        range.upper = range.upper.max(range.lower.clone());
+        range
+    }
+
+    // Resampling folds intervals in time order, backwards for descending time
+    pub fn for_resample(query: &HistoryQuery) -> Self {
+        Self {
+            reverse: query.sort_field == SortField::StartTime
+                && query.sort_order == SortOrder::Desc,
+            ..Self::time(query.start_time, query.end_time)
+        }
+    }
+
+    // Meta reads the first matching interval from each end of the time range
+    pub fn for_meta(query: &HistoryQuery, reverse: bool) -> Self {
+        Self {
+            reverse,
+            ..Self::time(query.start_time, query.end_time)
+        }
+    }
+
+    // Everything stored before this range, walked backwards
+    pub fn before(&self) -> Self {
+        Self {
+            scan: KvScan::Time,
+            lower: INTERVAL_KEY_PREFIX.to_vec(),
+            upper: self.lower.clone(),
+            reverse: true,
+        }
+    }
+
+    // Range a count walks. With a units filter the index keys carry the units, so
+    // only count filters need the values
+    pub fn for_count(query: &HistoryQuery) -> Self {
+        if query.units.is_bounded() {
+            Self::units(query.units)
+        } else {
+            Self::time(query.start_time, query.end_time)
+        }
+    }
+
+    // Whether the entry passes the filters, the value is only decoded for count filters
+    pub fn entry_matches(&self, query: &HistoryQuery, key: &[u8], value: &[u8]) -> bool {
+        if !self.key_matches(query, key) {
+            return false;
+        }
+        if !query.count.is_bounded() {
+            return true;
+        }
+        serde_json::from_slice::<RunepoolUnitsInterval>(value)
+            .is_ok_and(|interval| query.count.contains(interval.count))
+    }
+
+    // Whether the entry under the key passes the time and units filters, read from the key alone
+    fn key_matches(&self, query: &HistoryQuery, key: &[u8]) -> bool {
+        let fields = match self.scan {
+            KvScan::Time => key
+                .strip_prefix(INTERVAL_KEY_PREFIX)
+                .map(|rest| (None, rest)),
+            KvScan::Units => key.strip_prefix(UNITS_INDEX_PREFIX).and_then(|rest| {
+                let (units, rest) = rest.split_at_checked(UNITS_LEN)?;
+                Some((Some(u64::from_be_bytes(units.try_into().ok()?)), rest))
+            }),
+        };
+        let Some((units, times)) = fields else {
+            return false;
+        };
+        let Some((start_time, end_time)) = times
+            .split_at_checked(TIMESTAMP_LEN)
+            .and_then(|(start, end)| Some((decode_timestamp(start)?, decode_timestamp(end)?)))
+        else {
+            return false;
+        };
+
+        if !query.in_time_range(start_time, end_time) {
+            return false;
+        }
+
+        match units {
+            Some(units) => query.units.contains(units),
+            None => !query.units.is_bounded(),
+        }
+    }
+
+    // Whether the keys come out in the requested order so the page can stop early
+    fn is_ordered_for(&self, query: &HistoryQuery) -> bool {
+        match self.scan {
+            KvScan::Time => query.sort_field == SortField::StartTime,
+            KvScan::Units => {
+                query.sort_field == SortField::Units && query.sort_order == SortOrder::Asc
+            }
+        }
+    }
+}
+
+// Same order as the SQL backends. Time sorts break ties on the end time in the same
+// direction, like the primary keys scan, value sorts by start and end time ascending
+fn compare(query: &HistoryQuery, a: &RunepoolUnitsInterval, b: &RunepoolUnitsInterval) -> Ordering {
+    let ordering = match query.sort_field {
+        SortField::StartTime => (a.start_time, a.end_time).cmp(&(b.start_time, b.end_time)),
+        SortField::Units => a.units.cmp(&b.units),
+        SortField::Count => a.count.cmp(&b.count),
+    };
+    let ordering = match query.sort_order {
+        SortOrder::Asc => ordering,
+        SortOrder::Desc => ordering.reverse(),
+    };
+    ordering
+        .then(a.start_time.cmp(&b.start_time))
+        .then(a.end_time.cmp(&b.end_time))
+}
+
+// Applies filters, sorting, offset and limit to the intervals a scan yields. When the
+// scan is already in query order the page fills up and stops it early, otherwise
+// only the best offset + limit matches are kept while the scan runs
+pub struct Page<'q> {
+    query: &'q HistoryQuery,
+    ordered: bool,
+    // Off for resample buckets, the filters were applied to the intervals in them
+    filtered: bool,
+    skipped: u32,
+    results: Vec<RunepoolUnitsInterval>,
+}
+
+impl<'q> Page<'q> {
+    pub fn new(query: &'q HistoryQuery, range: &KvRange) -> Self {
+        Self {
+            query,
+            ordered: range.is_ordered_for(query),
+            filtered: true,
+            skipped: 0,
+            results: Vec::new(),
+        }
+    }
+
+    fn keep(&self) -> usize {
+        self.query.offset as usize + self.query.limit as usize
+    }
+
+    // Returns false once the page is full and the scan can stop
+    pub fn push(&mut self, interval: RunepoolUnitsInterval) -> bool {
+        if self.filtered
+            && (!self.query.matches(&interval) || !self.query.is_after_cursor(&interval))
+        {
+            return true;
+        }
+
+        if !self.ordered {
+            self.results.push(interval);
+            // Sorting once the buffer doubles keeps the memory bounded by the page
+            if self.results.len() >= 2 * self.keep().max(1) {
+                self.truncate();
+            }
+            return true;
+        }
+
+        if self.results.len() >= self.query.limit as usize {
+            return false;
+        }
+        if self.skipped < self.query.offset {
+            self.skipped += 1;
+            return true;
+        }
+
+        self.results.push(interval);
+        self.results.len() < self.query.limit as usize
+    }
+
+    fn truncate(&mut self) {
+        let query = self.query;
+        self.results.sort_by(|a, b| compare(query, a, b));
+        self.results.truncate(self.keep());
+    }
+
+    pub fn into_results(mut self) -> Vec<RunepoolUnitsInterval> {
+        if !self.ordered {
+            self.truncate();
+            self.results
+                .drain(..self.results.len().min(self.query.offset as usize));
+        }
+        self.results
+    }
+}
+
+// Bucket being filled while resampling
+struct Bucket {
+    start_time: DateTime<Utc>,
+    latest_start: DateTime<Utc>,
+    units: u64,
+    count: u64,
+}
+
+// Folds the intervals of a resample scan into buckets and pages the buckets. The
+// scan is in time order, so a bucket is done as soon as an interval falls outside it
+pub struct Buckets<'q> {
+    query: &'q HistoryQuery,
+    resample: Resample,
+    page: Page<'q>,
+    current: Option<Bucket>,
+}
+
+impl<'q> Buckets<'q> {
+    pub fn new(query: &'q HistoryQuery, resample: Resample, range: &KvRange) -> Self {
+        Self {
+            query,
+            resample,
+            page: Page {
+                filtered: false,
+                ..Page::new(query, range)
+            },
+            current: None,
+        }
+    }
+
+    // Returns false once the page is full and the scan can stop
+    pub fn push(&mut self, interval: RunepoolUnitsInterval) -> bool {
+        if !self.query.matches(&interval) {
+            return true;
+        }
+
+        let bucket_start = self.resample.interval.bucket_start(interval.start_time);
+        if let Some(bucket) = self
+            .current
+            .as_mut()
+            .filter(|bucket| bucket.start_time == bucket_start)
+        {
+            let latest = interval.start_time > bucket.latest_start;
+            if latest {
+                bucket.latest_start = interval.start_time;
+                bucket.units = interval.units;
+            }
+            match self.resample.count_mode {
+                CountMode::Sum => bucket.count += interval.count,
+                CountMode::Last if latest => bucket.count = interval.count,
+                CountMode::Last => {}
+            }
+            return true;
+        }
+
+        let bucket = Bucket {
+            start_time: bucket_start,
+            latest_start: interval.start_time,
+            units: interval.units,
+            count: interval.count,
+        };
+        match self.current.replace(bucket) {
+            Some(done) => self.finish(done),
+            None => true,
+        }
+    }
+
+    fn finish(&mut self, bucket: Bucket) -> bool {
+        self.page.push(RunepoolUnitsInterval {
+            start_time: bucket.start_time,
+            end_time: self.resample.interval.bucket_end(bucket.start_time),
+            count: bucket.count,
+            units: bucket.units,
+        })
+    }
+
+    pub fn into_results(mut self) -> Vec<RunepoolUnitsInterval> {
+        if let Some(bucket) = self.current.take() {
+            self.finish(bucket);
+        }
+        self.page.into_results()
+    }
+}
+
+// Pairs the intervals of a time scan with the interval stored before each of them and
+// pages the deltas. Ascending scans are seeded with the interval right before the
+// range, descending scans carry on below the range for the one before its oldest
+pub struct Deltas {
+    // Sorted by time and without the units/count filters, which deltas don't take
+    query: HistoryQuery,
+    // Ascending: the last interval seen. Descending: the newer interval waiting for
+    // the one stored before it
+    previous: Option<RunepoolUnitsInterval>,
+    skipped: u32,
+    results: Vec<RunepoolUnitsDelta>,
+}
+
+impl Deltas {
+    pub fn new(query: &HistoryQuery) -> Self {
+        Self {
+            query: HistoryQuery {
+                units: ValueRange::default(),
+                count: ValueRange::default(),
+                sort_field: SortField::StartTime,
+                ..query.clone()
+            },
+            previous: None,
+            skipped: 0,
+            results: Vec::new(),
+        }
+    }
+
+    // The page's time range, reaching down to the first key when descending
+    pub fn range(&self) -> KvRange {
+        let mut range = KvRange::for_page(&self.query);
+        if range.reverse {
+            range.lower = INTERVAL_KEY_PREFIX.to_vec();
+        }
+        range
+    }
+
+    // Where the interval before an ascending range is looked up, walked backwards
+    pub fn seed_range(&self) -> Option<KvRange> {
+        let range = self.range();
+        (!range.reverse).then(|| range.before())
+    }
+
+    pub fn seed(&mut self, previous: RunepoolUnitsInterval) {
+        self.previous = Some(previous);
+    }
+
+    // Returns false once the page is full or a descending scan went past the range
+    pub fn push(&mut self, interval: RunepoolUnitsInterval) -> bool {
+        if self.query.sort_order == SortOrder::Asc {
+            let previous = self.previous.replace(interval.clone());
+            return self.page(interval, previous.as_ref());
+        }
+
+        let Some(newer) = self.previous.replace(interval.clone()) else {
+            return true;
+        };
+        self.page(newer, Some(&interval))
+            && self
+                .query
+                .start_time
+                .is_none_or(|start| interval.start_time >= start)
+    }
+
+    fn page(
+        &mut self,
+        interval: RunepoolUnitsInterval,
+        previous: Option<&RunepoolUnitsInterval>,
+    ) -> bool {
+        if self.results.len() >= self.query.limit as usize {
+            return false;
+        }
+        if !self.query.matches(&interval) || !self.query.is_after_cursor(&interval) {
+            return true;
+        }
+        if self.skipped < self.query.offset {
+            self.skipped += 1;
+            return true;
+        }
+
+        self.results.push(RunepoolUnitsDelta::new(
+            interval,
+            previous.map(|previous| previous.units),
+            previous.map(|previous| previous.count),
+        ));
+        self.results.len() < self.query.limit as usize
+    }
+
+    pub fn into_results(mut self) -> Vec<RunepoolUnitsDelta> {
+        // The oldest interval of a descending scan that reached the first key
+        if self.query.sort_order == SortOrder::Desc {
+            if let Some(oldest) = self.previous.take() {
+                self.page(oldest, None);
+            }
+        }
+        self.results
+    }
+}
+
+// Running min/max/mean/variance of one field (Welford's method, so the variance of
+// large units values doesn't go through a sum of squares)
+#[derive(Debug, Clone, Copy)]
+struct RunningStats {
+    min: u64,
+    max: u64,
+    mean: f64,
+    m2: f64,
+    first: u64,
+    last: u64,
+}
+
+impl RunningStats {
+    fn new(value: u64) -> Self {
+        Self {
+            min: value,
+            max: value,
+            mean: value as f64,
+            m2: 0.0,
+            first: value,
+            last: value,
+        }
+    }
+
+    // `n` counts the values seen so far including this one
+    fn push(&mut self, value: u64, n: u64) {
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        let delta = value as f64 - self.mean;
+        self.mean += delta / n as f64;
+        self.m2 += delta * (value as f64 - self.mean);
+        self.last = value;
+    }
+
+    fn finish(self, n: u64) -> FieldStats {
+        let stddev = match n {
+            0 | 1 => 0.0,
+            n => (self.m2 / (n - 1) as f64).sqrt(),
+        };
+        FieldStats::new(self.min, self.max, self.mean, stddev, self.first, self.last)
+    }
+}
+
+struct SummaryState {
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    units: RunningStats,
+    count: RunningStats,
+}
+
+// Stats of the intervals of a time scan that pass the filters. The scan is in key
+// order, so the first interval pushed is the first in time and the last one the last
+pub struct Summary<'q> {
+    query: &'q HistoryQuery,
+    intervals: u64,
+    state: Option<SummaryState>,
+}
+
+impl<'q> Summary<'q> {
+    pub fn new(query: &'q HistoryQuery) -> Self {
+        Self {
+            query,
+            intervals: 0,
+            state: None,
+        }
+    }
+
+    pub fn push(&mut self, interval: RunepoolUnitsInterval) {
+        if !self.query.matches(&interval) {
+            return;
+        }
+
+        self.intervals += 1;
+        match self.state.as_mut() {
+            Some(state) => {
+                state.end_time = interval.end_time;
+                state.units.push(interval.units, self.intervals);
+                state.count.push(interval.count, self.intervals);
+            }
+            None => {
+                self.state = Some(SummaryState {
+                    start_time: interval.start_time,
+                    end_time: interval.end_time,
+                    units: RunningStats::new(interval.units),
+                    count: RunningStats::new(interval.count),
+                })
+            }
+        }
+    }
+
+    pub fn finish(self) -> Option<RunepoolUnitsStats> {
+        let state = self.state?;
+        Some(RunepoolUnitsStats {
+            start_time: state.start_time,
+            end_time: state.end_time,
+            intervals: self.intervals,
+            units: state.units.finish(self.intervals),
+            count: state.count.finish(self.intervals),
+        })
+    }
+}
+
+// New key for a key written before the binary layout ("{start}:{end}" in decimal)
+pub fn migrate_legacy_key(key: &[u8]) -> Option<Vec<u8>> {
+    let (start, end) = std::str::from_utf8(key).ok()?.split_once(':')?;
+    let start = DateTime::from_timestamp(start.parse().ok()?, 0)?;
+    let end = DateTime::from_timestamp(end.parse().ok()?, 0)?;
+    Some(interval_key(start, end))
+}
@@ -1,48 +1,439 @@
-use crate::core::models::runepool_units_history::RunepoolUnitsInterval;
-use crate::utils::metrics::{DatabaseOperation, DatabaseType, OperationMetrics};
+use super::{env_flag, kv, GapsScan, HistoryQuery, Resample, RunepoolStore};
+use crate::core::models::runepool_units_history::{
+    MetaStats, RunepoolUnitsDelta, RunepoolUnitsGaps, RunepoolUnitsInterval, RunepoolUnitsStats,
+};
+use crate::utils::metrics::{
+    log_db_operation_metrics, DatabaseOperation, DatabaseType, OperationMetrics,
+};
 use anyhow::Result;
-use std::sync::{Arc, Mutex};
+use chrono::{DateTime, Utc};
+use rusty_leveldb::LdbIterator;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
 
-pub async fn store_level_intervals(
+#[derive(Clone)]
+pub struct LevelStore {
     db: Arc<Mutex<rusty_leveldb::DB>>,
-    intervals: Vec<RunepoolUnitsInterval>,
-) -> Result<(), anyhow::Error> {
-    tracing::info!("Starting to store {} intervals in LevelDB", intervals.len());
-
-    let metrics = OperationMetrics::new(
-        DatabaseType::LevelDB,
-        DatabaseOperation::Write,
-        intervals.len(),
-        "rune pool history".to_string(),
-    );
-
-    let mut db_lock = db
-        .lock()
-        .map_err(|_| anyhow::anyhow!("Failed to acquire LevelDB lock"))?;
-
-    let mut stored_count = 0;
-    for interval in intervals {
-        let key = format!(
-            "{}:{}",
-            interval.start_time.timestamp(),
-            interval.end_time.timestamp()
-        );
-
-        // Check if record exists
-        if db_lock.get(key.as_bytes()).is_none() {
-            let value = serde_json::to_vec(&interval)?;
-            db_lock.put(key.as_bytes(), &value)?;
-            stored_count += 1;
-        }
-    }
-
-    // Ensure data is written to disk
-    db_lock.flush()?;
-
-    tracing::info!(
-        "Successfully stored {} new intervals in LevelDB",
-        stored_count
-    );
-    metrics.finish();
-    Ok(())
+    sync_writes: bool,
+}
+
+impl LevelStore {
+    pub fn new(db: Arc<Mutex<rusty_leveldb::DB>>) -> Self {
+        Self {
+            db,
+            sync_writes: env_flag("LEVELDB_SYNC_WRITES"),
+        }
+    }
+
+    // Whether every write batch is fsynced before the insert returns
+    pub fn with_sync_writes(mut self, sync_writes: bool) -> Self {
+        self.sync_writes = sync_writes;
+        self
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, rusty_leveldb::DB>> {
+        self.db
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Failed to acquire LevelDB lock"))
+    }
+
+    // Walks the raw entries of the range in key order (or reverse key order) until
+    // `visit` returns false. LdbIterator::next advances before reading, so the loop goes
+    // through valid/current/advance to not lose the entry the seek landed on.
+    // DBIterator::prev can land on deleted or outdated versions of a key, so entries
+    // walked in reverse are read again with a point lookup
+    fn visit_raw(
+        &self,
+        range: &kv::KvRange,
+        mut visit: impl FnMut(&[u8], &[u8]) -> bool,
+    ) -> Result<()> {
+        let mut db_lock = self.lock()?;
+        let mut iter = db_lock
+            .new_iter()
+            .map_err(|e| anyhow::anyhow!("Failed to create iterator: {}", e))?;
+        if range.reverse {
+            seek_before(&mut iter, &range.lower, &range.upper);
+        } else {
+            iter.seek(&range.lower);
+        }
+
+        let (mut key, mut value) = (Vec::new(), Vec::new());
+        while iter.valid() && iter.current(&mut key, &mut value) {
+            if key < range.lower || key >= range.upper {
+                break;
+            }
+
+            if range.reverse {
+                match db_lock.get(&key) {
+                    Some(latest) => value = latest,
+                    None => {
+                        iter.prev();
+                        continue;
+                    }
+                }
+            }
+
+            if !visit(&key, &value) {
+                break;
+            }
+
+            if range.reverse {
+                iter.prev();
+            } else {
+                iter.advance();
+            }
+        }
+        Ok(())
+    }
+
+    fn visit_entries(
+        &self,
+        range: &kv::KvRange,
+        mut visit: impl FnMut(Vec<u8>, RunepoolUnitsInterval) -> bool,
+    ) -> Result<()> {
+        self.visit_raw(range, |key, value| match serde_json::from_slice(value) {
+            Ok(interval) => visit(key.to_vec(), interval),
+            Err(e) => {
+                tracing::error!("Failed to deserialize interval from LevelDB: {}", e);
+                true
+            }
+        })
+    }
+
+    // Every stored interval inside the time range along with its primary key, in key order
+    fn scan(
+        &self,
+        start: Option<DateTime<Utc>>,
+        end: Option<DateTime<Utc>>,
+    ) -> Result<Vec<(Vec<u8>, RunepoolUnitsInterval)>> {
+        let mut entries = Vec::new();
+        self.visit_entries(&kv::KvRange::time(start, end), |key, interval| {
+            entries.push((key, interval));
+            true
+        })?;
+        Ok(entries)
+    }
+
+    // First interval of the range that passes the filters
+    fn first_match(
+        &self,
+        range: &kv::KvRange,
+        query: &HistoryQuery,
+    ) -> Result<Option<RunepoolUnitsInterval>> {
+        let mut found = None;
+        self.visit_entries(range, |_, interval| {
+            if query.matches(&interval) {
+                found = Some(interval);
+            }
+            found.is_none()
+        })?;
+        Ok(found)
+    }
+
+    // Indexes intervals stored before the units index existed
+    pub fn ensure_units_index(&self) -> Result<usize> {
+        let intervals = self.scan(None, None)?;
+
+        let mut db_lock = self.lock()?;
+        let mut batch = rusty_leveldb::WriteBatch::default();
+        for (_, interval) in &intervals {
+            let index_key = kv::units_index_key(interval);
+            if db_lock.get(&index_key).is_none() {
+                batch.put(&index_key, &serde_json::to_vec(interval)?);
+            }
+        }
+
+        let indexed = batch.count() as usize;
+        db_lock.write(batch, self.sync_writes)?;
+
+        tracing::info!("Indexed {} LevelDB intervals by units", indexed);
+        Ok(indexed)
+    }
+
+    // Rewrites keys from the old "{start}:{end}" string layout to the binary layout
+    pub fn migrate_legacy_keys(&self) -> Result<usize> {
+        let mut db_lock = self.lock()?;
+        let mut iter = db_lock
+            .new_iter()
+            .map_err(|e| anyhow::anyhow!("Failed to create iterator: {}", e))?;
+        iter.seek_to_first();
+
+        let mut batch = rusty_leveldb::WriteBatch::default();
+        let (mut key, mut value) = (Vec::new(), Vec::new());
+        while iter.valid() && iter.current(&mut key, &mut value) {
+            if let Some(new_key) = kv::migrate_legacy_key(&key) {
+                batch.put(&new_key, &value);
+                batch.delete(&key);
+            }
+            iter.advance();
+        }
+        drop(iter);
+
+        // Every rewritten key is a put plus a delete
+        let migrated = batch.count() as usize / 2;
+        db_lock.write(batch, self.sync_writes)?;
+
+        tracing::info!("Migrated {} legacy LevelDB keys", migrated);
+        Ok(migrated)
+    }
+}
+
+// Positions the iterator on the last key before `upper`. rusty_leveldb has no
+// seek_to_last, so when nothing sorts after the range it walks up from `lower`
+fn seek_before(iter: &mut rusty_leveldb::DBIterator, lower: &[u8], upper: &[u8]) {
+    iter.seek(upper);
+    if iter.valid() {
+        iter.prev();
+        return;
+    }
+
+    iter.seek(lower);
+    let (mut key, mut value) = (Vec::new(), Vec::new());
+    let mut last = None;
+    while iter.valid() && iter.current(&mut key, &mut value) && key.as_slice() < upper {
+        last = Some(key.clone());
+        iter.advance();
+    }
+    match last {
+        Some(last) => iter.seek(&last),
+        None => iter.reset(),
+    }
+}
+
+impl RunepoolStore for LevelStore {
+    const DATABASE_TYPE: DatabaseType = DatabaseType::LevelDB;
+
+    async fn insert(&self, intervals: &[RunepoolUnitsInterval]) -> Result<usize> {
+        tracing::info!("Starting to store {} intervals in LevelDB", intervals.len());
+
+        let mut db_lock = self.lock()?;
+
+        let keys: Vec<Vec<u8>> = intervals.iter().map(kv::key_for_interval).collect();
+
+        // Read before write is timed on its own so it can be told apart from the write cost
+        let read_metrics = OperationMetrics::new(
+            DatabaseType::LevelDB,
+            DatabaseOperation::Read,
+            keys.len(),
+            "rune pool history (existing keys)".to_string(),
+        );
+        let existing: Vec<bool> = keys.iter().map(|key| db_lock.get(key).is_some()).collect();
+        read_metrics.finish();
+
+        let metrics = OperationMetrics::new(
+            DatabaseType::LevelDB,
+            DatabaseOperation::Write,
+            intervals.len(),
+            "rune pool history (write batch)".to_string(),
+        );
+
+        // Interval and its units index entry go in the same batch so they never drift apart
+        let mut batch = rusty_leveldb::WriteBatch::default();
+        let mut batched_keys = HashSet::new();
+        for ((interval, key), exists) in intervals.iter().zip(&keys).zip(existing) {
+            if !exists && batched_keys.insert(key) {
+                let value = serde_json::to_vec(interval)?;
+                batch.put(key, &value);
+                batch.put(&kv::units_index_key(interval), &value);
+            }
+        }
+
+        // The whole batch lands atomically
+        let stored_count = batched_keys.len();
+        db_lock.write(batch, self.sync_writes)?;
+
+        tracing::info!(
+            "Successfully stored {} new intervals in LevelDB",
+            stored_count
+        );
+        metrics.finish();
+        Ok(stored_count)
+    }
+
+    async fn query(&self, query: &HistoryQuery) -> Result<Vec<RunepoolUnitsInterval>> {
+        let metrics = OperationMetrics::new(
+            DatabaseType::LevelDB,
+            DatabaseOperation::Read,
+            query.limit as usize,
+            "runepool units".to_string(),
+        );
+
+        let operation_start = Instant::now();
+
+        let range = kv::KvRange::for_page(query);
+        let mut page = kv::Page::new(query, &range);
+        self.visit_entries(&range, |_, interval| page.push(interval))?;
+        let results = page.into_results();
+
+        // Log metrics
+        log_db_operation_metrics(
+            &format!("read_intervals_{}_records", results.len()),
+            operation_start,
+        );
+        metrics.finish();
+
+        Ok(results)
+    }
+
+    async fn resample(
+        &self,
+        query: &HistoryQuery,
+        resample: Resample,
+    ) -> Result<Vec<RunepoolUnitsInterval>> {
+        let mut metrics = OperationMetrics::new(
+            DatabaseType::LevelDB,
+            DatabaseOperation::Aggregate,
+            0,
+            format!("runepool units (by {})", resample.interval),
+        );
+
+        let range = kv::KvRange::for_resample(query);
+        let mut buckets = kv::Buckets::new(query, resample, &range);
+        self.visit_entries(&range, |_, interval| buckets.push(interval))?;
+        let results = buckets.into_results();
+
+        metrics.set_record_count(results.len());
+        metrics.finish();
+        Ok(results)
+    }
+
+    async fn deltas(&self, query: &HistoryQuery) -> Result<Vec<RunepoolUnitsDelta>> {
+        let mut metrics = OperationMetrics::new(
+            DatabaseType::LevelDB,
+            DatabaseOperation::Read,
+            0,
+            "runepool unit deltas".to_string(),
+        );
+
+        // One streaming pass over the keys in time order, plus a single step back for
+        // the interval before an ascending page
+        let mut deltas = kv::Deltas::new(query);
+        if let Some(seed_range) = deltas.seed_range() {
+            self.visit_entries(&seed_range, |_, interval| {
+                deltas.seed(interval);
+                false
+            })?;
+        }
+        let range = deltas.range();
+        self.visit_entries(&range, |_, interval| deltas.push(interval))?;
+        let results = deltas.into_results();
+
+        metrics.set_record_count(results.len());
+        metrics.finish();
+        Ok(results)
+    }
+
+    async fn meta(&self, query: &HistoryQuery) -> Result<Option<MetaStats>> {
+        let mut metrics = OperationMetrics::new(
+            DatabaseType::LevelDB,
+            DatabaseOperation::Read,
+            0,
+            "runepool units (meta)".to_string(),
+        );
+
+        // Forward and backward from the ends of the time range, both stop at the first match
+        let Some(first) = self.first_match(&kv::KvRange::for_meta(query, false), query)? else {
+            metrics.finish();
+            return Ok(None);
+        };
+        let last = self
+            .first_match(&kv::KvRange::for_meta(query, true), query)?
+            .unwrap_or_else(|| first.clone());
+
+        metrics.set_record_count(2);
+        metrics.finish();
+        Ok(Some(MetaStats::new(&first, &last)))
+    }
+
+    async fn stats(&self, query: &HistoryQuery) -> Result<Option<RunepoolUnitsStats>> {
+        let mut metrics = OperationMetrics::new(
+            DatabaseType::LevelDB,
+            DatabaseOperation::Stats,
+            0,
+            "runepool units".to_string(),
+        );
+
+        // Summarized in rust over a time ordered scan
+        let mut summary = kv::Summary::new(query);
+        let range = kv::KvRange::time(query.start_time, query.end_time);
+        self.visit_entries(&range, |_, interval| {
+            summary.push(interval);
+            true
+        })?;
+        let stats = summary.finish();
+
+        metrics.set_record_count(stats.as_ref().map_or(0, |stats| stats.intervals as usize));
+        metrics.finish();
+        Ok(stats)
+    }
+
+    async fn gaps(&self, query: &HistoryQuery) -> Result<Option<RunepoolUnitsGaps>> {
+        let mut metrics = OperationMetrics::new(
+            DatabaseType::LevelDB,
+            DatabaseOperation::Read,
+            0,
+            "runepool unit gaps (key range)".to_string(),
+        );
+
+        // The keys carry the times and come in time order, values are never read
+        let mut scan = GapsScan::default();
+        let range = kv::KvRange::time(query.start_time, query.end_time);
+        self.visit_raw(&range, |key, _| {
+            if let Some((start, end)) = kv::interval_key_times(key) {
+                if query.in_time_range(start, end) {
+                    scan.push(start, end);
+                }
+            }
+            true
+        })?;
+        let gaps = scan.finish();
+
+        metrics.set_record_count(gaps.as_ref().map_or(0, |gaps| gaps.intervals as usize));
+        metrics.finish();
+        Ok(gaps)
+    }
+
+    async fn count(&self, query: &HistoryQuery) -> Result<u64> {
+        let mut metrics = OperationMetrics::new(
+            DatabaseType::LevelDB,
+            DatabaseOperation::Count,
+            0,
+            "runepool units (key range)".to_string(),
+        );
+
+        // Keys are enough unless the query filters on count
+        let range = kv::KvRange::for_count(query);
+        let mut count = 0;
+        self.visit_raw(&range, |key, value| {
+            if range.entry_matches(query, key, value) {
+                count += 1;
+            }
+            true
+        })?;
+
+        metrics.set_record_count(count as usize);
+        metrics.finish();
+        Ok(count)
+    }
+
+    async fn delete_range(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<usize> {
+        let mut batch = rusty_leveldb::WriteBatch::default();
+        let mut deleted = 0;
+        for (key, interval) in self.scan(Some(start), Some(end))? {
+            if interval.start_time >= start && interval.end_time <= end {
+                batch.delete(&key);
+                batch.delete(&kv::units_index_key(&interval));
+                deleted += 1;
+            }
+        }
+
+        self.lock()?.write(batch, self.sync_writes)?;
+        Ok(deleted)
+    }
+
+    async fn health(&self) -> Result<()> {
+        let _db_lock = self.lock()?;
+        Ok(())
+    }
 }
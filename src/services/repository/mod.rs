@@ -1,6 +1,191 @@
+pub mod downsampling;
+pub mod filling;
+pub mod kv;
 pub mod leveldb;
 pub mod mongodb;
 pub mod postgres;
 pub mod rocksdb;
 pub mod runepool;
+pub mod smoothing;
 pub mod surrealdb;
+
+use crate::core::models::common::{
+    CountMode, HistoryCursor, Interval, SortField, SortOrder, ValueRange,
+};
+use crate::core::models::runepool_units_history::{
+    IntervalBreak, MetaStats, RunepoolUnitsDelta, RunepoolUnitsGaps, RunepoolUnitsInterval,
+    RunepoolUnitsStats,
+};
+use crate::utils::metrics::DatabaseType;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use std::future::Future;
+
+// True when the env variable is set to 1 or true
+pub(crate) fn env_flag(name: &str) -> bool {
+    matches!(
+        std::env::var(name)
+            .unwrap_or_default()
+            .to_lowercase()
+            .as_str(),
+        "1" | "true"
+    )
+}
+
+// Filter and cursor values as the signed integers Postgres, MongoDB and SurrealDB
+// store, anything past i64::MAX is past every stored value so it clamps instead of
+// wrapping negative
+pub(crate) fn stored_int(value: u64) -> i64 {
+    i64::try_from(value).unwrap_or(i64::MAX)
+}
+
+// Filters, sorting and pagination shared by every backend
+#[derive(Debug, Clone)]
+pub struct HistoryQuery {
+    pub limit: u32,
+    pub offset: u32,
+    // Half-open [start_time, end_time): intervals starting at or after start_time
+    // and ending by end_time, either end can be left open
+    pub start_time: Option<DateTime<Utc>>,
+    pub end_time: Option<DateTime<Utc>>,
+    pub units: ValueRange,
+    pub count: ValueRange,
+    pub sort_field: SortField,
+    pub sort_order: SortOrder,
+    // Keyset pagination, only intervals sorting after the cursor are returned
+    pub after: Option<HistoryCursor>,
+}
+
+impl HistoryQuery {
+    pub fn has_time_filter(&self) -> bool {
+        self.start_time.is_some() || self.end_time.is_some()
+    }
+
+    pub fn in_time_range(&self, start_time: DateTime<Utc>, end_time: DateTime<Utc>) -> bool {
+        self.start_time.is_none_or(|start| start_time >= start)
+            && self.end_time.is_none_or(|end| end_time <= end)
+    }
+
+    // True when the interval passes the filters (used by the KV stores that filter in rust)
+    pub fn matches(&self, interval: &RunepoolUnitsInterval) -> bool {
+        if !self.in_time_range(interval.start_time, interval.end_time) {
+            return false;
+        }
+
+        self.units.contains(interval.units) && self.count.contains(interval.count)
+    }
+
+    pub fn is_after_cursor(&self, interval: &RunepoolUnitsInterval) -> bool {
+        self.after.is_none_or(|cursor| cursor.precedes(interval))
+    }
+}
+
+// Buckets a resampled query folds the stored intervals into. Each bucket spans
+// [bucket start, next bucket start) and keeps the units of its latest interval
+#[derive(Debug, Clone, Copy)]
+pub struct Resample {
+    pub interval: Interval,
+    pub count_mode: CountMode,
+}
+
+// Walks intervals in time order for the gaps report of the backends that can't compare
+// neighbours in the database, only the interval before the current one is kept
+#[derive(Debug, Default)]
+pub struct GapsScan {
+    intervals: u64,
+    first_start: Option<DateTime<Utc>>,
+    previous: Option<(DateTime<Utc>, DateTime<Utc>)>,
+    breaks: Vec<IntervalBreak>,
+}
+
+impl GapsScan {
+    pub fn push(&mut self, start_time: DateTime<Utc>, end_time: DateTime<Utc>) {
+        self.intervals += 1;
+        self.first_start.get_or_insert(start_time);
+        if let Some((previous_start, previous_end)) = self.previous.replace((start_time, end_time))
+        {
+            if previous_end != start_time || previous_start == start_time {
+                self.breaks.push(IntervalBreak {
+                    previous_start,
+                    previous_end,
+                    start_time,
+                    end_time,
+                });
+            }
+        }
+    }
+
+    pub fn finish(self) -> Option<RunepoolUnitsGaps> {
+        let first_start = self.first_start?;
+        let (_, last_end) = self.previous?;
+        Some(RunepoolUnitsGaps::new(
+            first_start,
+            last_end,
+            self.intervals,
+            self.breaks,
+        ))
+    }
+}
+
+// One implementation per database, adding a new database to compare means implementing this
+pub trait RunepoolStore: Clone + Send + Sync + 'static {
+    const DATABASE_TYPE: DatabaseType;
+
+    // Stores the intervals and returns how many of them were new
+    fn insert(
+        &self,
+        intervals: &[RunepoolUnitsInterval],
+    ) -> impl Future<Output = Result<usize>> + Send;
+
+    fn query(
+        &self,
+        query: &HistoryQuery,
+    ) -> impl Future<Output = Result<Vec<RunepoolUnitsInterval>>> + Send;
+
+    // Filters apply to the stored intervals, sorting, offset and limit to the buckets.
+    // The cursor is ignored
+    fn resample(
+        &self,
+        query: &HistoryQuery,
+        resample: Resample,
+    ) -> impl Future<Output = Result<Vec<RunepoolUnitsInterval>>> + Send;
+
+    // Intervals in the time range, each with its change against the interval stored
+    // right before it, which can be outside the range. Always sorted by start time in
+    // the query's order, units/count filters are ignored
+    fn deltas(
+        &self,
+        query: &HistoryQuery,
+    ) -> impl Future<Output = Result<Vec<RunepoolUnitsDelta>>> + Send;
+
+    // Meta of every interval matching the filters, from the earliest and the latest one
+    // by time. None when nothing matches. Sorting, limit/offset and the cursor are ignored
+    fn meta(&self, query: &HistoryQuery) -> impl Future<Output = Result<Option<MetaStats>>> + Send;
+
+    // Summary of the intervals matching the filters, None when nothing matches.
+    // Sorting, limit/offset and the cursor are ignored
+    fn stats(
+        &self,
+        query: &HistoryQuery,
+    ) -> impl Future<Output = Result<Option<RunepoolUnitsStats>>> + Send;
+
+    // Gaps, overlaps and duplicate starts between neighbouring intervals in the time range,
+    // walked in time order. None when nothing is in range. Units/count filters, sorting,
+    // limit/offset and the cursor are ignored
+    fn gaps(
+        &self,
+        query: &HistoryQuery,
+    ) -> impl Future<Output = Result<Option<RunepoolUnitsGaps>>> + Send;
+
+    // Number of intervals matching the filters, limit/offset/cursor are ignored
+    fn count(&self, query: &HistoryQuery) -> impl Future<Output = Result<u64>> + Send;
+
+    // Deletes every interval inside [start, end] and returns how many were removed
+    fn delete_range(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> impl Future<Output = Result<usize>> + Send;
+
+    fn health(&self) -> impl Future<Output = Result<()>> + Send;
+}
@@ -1,49 +1,643 @@
-use crate::config::connect::MONGO_CLIENT;
-use crate::core::models::runepool_units_history::RunepoolUnitsInterval;
-use crate::utils::metrics::{DatabaseOperation, DatabaseType, OperationMetrics};
+use super::{stored_int, HistoryQuery, Resample, RunepoolStore};
+use crate::core::models::common::{
+    CountMode, HistoryCursor, Interval, SortField, SortOrder, ValueRange,
+};
+use crate::core::models::runepool_units_history::{
+    FieldStats, IntervalBreak, MetaStats, RunepoolUnitsDelta, RunepoolUnitsGaps,
+    RunepoolUnitsInterval, RunepoolUnitsStats,
+};
+use crate::utils::metrics::{
+    log_db_operation_metrics, DatabaseOperation, DatabaseType, OperationMetrics,
+};
 use anyhow::Result;
 use bson::{doc, Document};
-use chrono::Utc;
-
-// Store in MongoDB
-pub async fn store_mongo_intervals(
-    intervals: Vec<RunepoolUnitsInterval>,
-) -> Result<usize, mongodb::error::Error> {
-    let metrics = OperationMetrics::new(
-        DatabaseType::MongoDB,
-        DatabaseOperation::Write,
-        intervals.len(),
-        "runepool units".to_string(),
-    );
-
-    let client = MONGO_CLIENT.get().expect("MongoDB client not initialized");
-    let db = client.database("runepool");
-    let collection = db.collection::<Document>("runepool_unit_intervals");
-
-    let mut stored_count = 0;
-    for interval in intervals {
-        // Check if record exists
-        let filter = doc! {
-            "start_time": interval.start_time,
-            "end_time": interval.end_time
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use mongodb::error::{ErrorKind, InsertManyError};
+use mongodb::options::{FindOneOptions, FindOptions, IndexOptions};
+use mongodb::{Collection, IndexModel};
+use std::time::Instant;
+
+#[derive(Clone)]
+pub struct MongoStore {
+    client: mongodb::Client,
+}
+
+impl MongoStore {
+    pub fn new(client: mongodb::Client) -> Self {
+        Self { client }
+    }
+
+    fn collection(&self) -> Collection<Document> {
+        self.client
+            .database("runepool")
+            .collection::<Document>("runepool_unit_intervals")
+    }
+
+    // Unique (start_time, end_time) index so mongo itself rejects duplicate intervals
+    pub async fn ensure_indexes(&self) -> Result<()> {
+        let index = IndexModel::builder()
+            .keys(doc! { "start_time": 1, "end_time": 1 })
+            .options(
+                IndexOptions::builder()
+                    .name("start_time_end_time_unique".to_string())
+                    .unique(true)
+                    .build(),
+            )
+            .build();
+
+        // (sort value, start_time) indexes back keyset pagination when sorting by units or count
+        let sort_indexes = ["units", "count"].map(|field| {
+            IndexModel::builder()
+                .keys(doc! { field: 1, "start_time": 1 })
+                .build()
+        });
+
+        let collection = self.collection();
+        collection.create_index(index).await?;
+        collection.create_indexes(sort_indexes).await?;
+        tracing::info!("MongoDB indexes are in place");
+        Ok(())
+    }
+
+    // Single unordered insert_many, intervals already stored hit the unique index and
+    // are counted as skipped instead of failing the whole batch
+    pub async fn insert_many_unordered(
+        &self,
+        intervals: &[RunepoolUnitsInterval],
+    ) -> Result<MongoInsertOutcome> {
+        if intervals.is_empty() {
+            return Ok(MongoInsertOutcome::default());
+        }
+
+        let created_at = Utc::now();
+        let docs: Vec<Document> = intervals
+            .iter()
+            .map(|interval| {
+                doc! {
+                    "start_time": interval.start_time,
+                    "end_time": interval.end_time,
+                    "count": interval.count as i64,
+                    "units": interval.units as i64,
+                    "created_at": created_at
+                }
+            })
+            .collect();
+
+        match self.collection().insert_many(docs).ordered(false).await {
+            Ok(res) => Ok(MongoInsertOutcome {
+                inserted: res.inserted_ids.len(),
+                ..Default::default()
+            }),
+            Err(e) => match e.kind.as_ref() {
+                ErrorKind::InsertMany(InsertManyError {
+                    write_errors: Some(write_errors),
+                    write_concern_error: None,
+                    ..
+                }) => {
+                    let skipped = write_errors
+                        .iter()
+                        .filter(|error| error.code == DUPLICATE_KEY_ERROR_CODE)
+                        .count();
+                    Ok(MongoInsertOutcome {
+                        inserted: intervals.len() - write_errors.len(),
+                        skipped,
+                        failed: write_errors.len() - skipped,
+                    })
+                }
+                _ => Err(e.into()),
+            },
+        }
+    }
+}
+
+const DUPLICATE_KEY_ERROR_CODE: i32 = 11000;
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MongoInsertOutcome {
+    pub inserted: usize,
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+fn filter_document(query: &HistoryQuery) -> Document {
+    let mut filter = doc! {};
+
+    if let Some(start) = query.start_time {
+        filter.insert("start_time", doc! { "$gte": start });
+    }
+
+    if let Some(end) = query.end_time {
+        filter.insert("end_time", doc! { "$lte": end });
+    }
+
+    for (field, range) in [("units", query.units), ("count", query.count)] {
+        if range.is_bounded() {
+            filter.insert(field, value_range_document(range));
+        }
+    }
+
+    filter
+}
+
+fn sort_document(query: &HistoryQuery) -> Document {
+    let sort_direction = match query.sort_order {
+        SortOrder::Asc => 1,
+        SortOrder::Desc => -1,
+    };
+    // Same tie break as the SQL backends so pages never overlap on equal values
+    let mut sort = doc! { query.sort_field.column(): sort_direction };
+    if query.sort_field == SortField::StartTime {
+        sort.insert("end_time", sort_direction);
+    } else {
+        sort.insert("start_time", 1);
+        sort.insert("end_time", 1);
+    }
+    sort
+}
+
+// $dateTrunc unit and bin size of a bucket
+fn bucket_unit(interval: Interval) -> (&'static str, i64) {
+    match interval {
+        Interval::FiveMin => ("minute", 5),
+        Interval::Hour => ("hour", 1),
+        Interval::Day => ("day", 1),
+        Interval::Week => ("week", 1),
+        Interval::Month => ("month", 1),
+        Interval::Quarter => ("quarter", 1),
+        Interval::Year => ("year", 1),
+    }
+}
+
+fn value_range_document(range: ValueRange) -> Document {
+    let mut bounds = doc! {};
+    if let Some(min) = range.min {
+        bounds.insert("$gte", stored_int(min));
+    }
+    if let Some(max) = range.max {
+        bounds.insert("$lte", stored_int(max));
+    }
+    bounds
+}
+
+fn interval_from_document(doc: &Document) -> Result<RunepoolUnitsInterval> {
+    Ok(RunepoolUnitsInterval {
+        start_time: doc.get_datetime("start_time")?.to_chrono(),
+        end_time: doc.get_datetime("end_time")?.to_chrono(),
+        count: doc.get_i64("count")? as u64,
+        units: doc.get_i64("units")? as u64,
+    })
+}
+
+// $group accumulators for the stats of one field. Run over intervals sorted by time,
+// so $first/$last are the first and last values in time
+fn stats_accumulators(group: &mut Document, field: &str) {
+    let path = format!("${}", field);
+    group.insert(format!("{}_min", field), doc! { "$min": &path });
+    group.insert(format!("{}_max", field), doc! { "$max": &path });
+    group.insert(format!("{}_avg", field), doc! { "$avg": &path });
+    group.insert(format!("{}_stddev", field), doc! { "$stdDevSamp": &path });
+    group.insert(format!("{}_first", field), doc! { "$first": &path });
+    group.insert(format!("{}_last", field), doc! { "$last": &path });
+}
+
+fn field_stats(doc: &Document, field: &str) -> Result<FieldStats> {
+    let value = |stat: &str| doc.get_i64(format!("{}_{}", field, stat));
+    // $stdDevSamp is null for a single interval
+    let stddev = doc.get_f64(format!("{}_stddev", field)).unwrap_or_default();
+    Ok(FieldStats::new(
+        value("min")? as u64,
+        value("max")? as u64,
+        doc.get_f64(format!("{}_avg", field))?,
+        stddev,
+        value("first")? as u64,
+        value("last")? as u64,
+    ))
+}
+
+// Keyset condition for the intervals after the cursor, ties go by start time ascending
+fn after_cursor_document(cursor: &HistoryCursor) -> Document {
+    let operator = match cursor.sort_order {
+        SortOrder::Asc => "$gt",
+        SortOrder::Desc => "$lt",
+    };
+    let (field, value) = match cursor.sort_field {
+        SortField::StartTime => return doc! { "start_time": { operator: cursor.start_time } },
+        SortField::Units => ("units", stored_int(cursor.value)),
+        SortField::Count => ("count", stored_int(cursor.value)),
+    };
+
+    doc! {
+        "$or": [
+            { field: { operator: value } },
+            { field: value, "start_time": { "$gt": cursor.start_time } }
+        ]
+    }
+}
+
+impl RunepoolStore for MongoStore {
+    const DATABASE_TYPE: DatabaseType = DatabaseType::MongoDB;
+
+    async fn insert(&self, intervals: &[RunepoolUnitsInterval]) -> Result<usize> {
+        let metrics = OperationMetrics::new(
+            DatabaseType::MongoDB,
+            DatabaseOperation::Write,
+            intervals.len(),
+            "runepool units".to_string(),
+        );
+
+        let outcome = self.insert_many_unordered(intervals).await?;
+        tracing::info!(
+            "MongoDB insert: {} inserted, {} skipped, {} failed",
+            outcome.inserted,
+            outcome.skipped,
+            outcome.failed
+        );
+        metrics.finish();
+
+        // Duplicates are expected on re-ingest, any other write error is not
+        if outcome.failed > 0 {
+            return Err(anyhow::anyhow!(
+                "{} intervals failed to insert in MongoDB ({} inserted)",
+                outcome.failed,
+                outcome.inserted
+            ));
+        }
+        Ok(outcome.inserted)
+    }
+
+    async fn query(&self, query: &HistoryQuery) -> Result<Vec<RunepoolUnitsInterval>> {
+        let metrics = OperationMetrics::new(
+            DatabaseType::MongoDB,
+            DatabaseOperation::Read,
+            query.limit as usize,
+            "runepool units".to_string(),
+        );
+
+        let collection = self.collection();
+        let mut filter = filter_document(query);
+        if let Some(cursor) = &query.after {
+            filter.insert("$and", vec![after_cursor_document(cursor)]);
+        }
+
+        let find_options = FindOptions::builder()
+            .sort(sort_document(query))
+            .skip(query.offset as u64)
+            .limit(query.limit as i64)
+            .build();
+
+        let start_time = Instant::now();
+        let mut cursor = collection.find(filter).with_options(find_options).await?;
+
+        let mut results = Vec::new();
+        while let Some(doc) = cursor.try_next().await? {
+            results.push(interval_from_document(&doc)?);
+        }
+
+        log_db_operation_metrics(
+            &format!("read_intervals_{}_records", results.len()),
+            start_time,
+        );
+
+        metrics.finish();
+        Ok(results)
+    }
+
+    async fn resample(
+        &self,
+        query: &HistoryQuery,
+        resample: Resample,
+    ) -> Result<Vec<RunepoolUnitsInterval>> {
+        let mut metrics = OperationMetrics::new(
+            DatabaseType::MongoDB,
+            DatabaseOperation::Aggregate,
+            0,
+            format!("runepool units (by {})", resample.interval),
+        );
+
+        let (unit, bin_size) = bucket_unit(resample.interval);
+        let count = match resample.count_mode {
+            CountMode::Last => doc! { "$last": "$count" },
+            CountMode::Sum => doc! { "$sum": "$count" },
+        };
+        // Sorted by time before grouping so $last is the latest interval of each bucket
+        let pipeline = vec![
+            doc! { "$match": filter_document(query) },
+            doc! { "$sort": { "start_time": 1 } },
+            doc! { "$group": {
+                "_id": { "$dateTrunc": {
+                    "date": "$start_time",
+                    "unit": unit,
+                    "binSize": bin_size,
+                    "timezone": "UTC",
+                    "startOfWeek": "monday"
+                } },
+                "units": { "$last": "$units" },
+                "count": count
+            } },
+            doc! { "$project": {
+                "_id": 0,
+                "start_time": "$_id",
+                "end_time": { "$dateAdd": {
+                    "startDate": "$_id",
+                    "unit": unit,
+                    "amount": bin_size,
+                    "timezone": "UTC"
+                } },
+                "units": 1,
+                "count": 1
+            } },
+            doc! { "$sort": sort_document(query) },
+            doc! { "$skip": query.offset as i64 },
+            doc! { "$limit": query.limit as i64 },
+        ];
+
+        let mut cursor = self.collection().aggregate(pipeline).await?;
+        let mut results = Vec::new();
+        while let Some(doc) = cursor.try_next().await? {
+            results.push(RunepoolUnitsInterval {
+                start_time: doc.get_datetime("start_time")?.to_chrono(),
+                end_time: doc.get_datetime("end_time")?.to_chrono(),
+                count: doc.get_i64("count")? as u64,
+                units: doc.get_i64("units")? as u64,
+            });
+        }
+
+        metrics.set_record_count(results.len());
+        metrics.finish();
+        Ok(results)
+    }
+
+    async fn deltas(&self, query: &HistoryQuery) -> Result<Vec<RunepoolUnitsDelta>> {
+        let mut metrics = OperationMetrics::new(
+            DatabaseType::MongoDB,
+            DatabaseOperation::Read,
+            0,
+            "runepool unit deltas".to_string(),
+        );
+
+        // No $setWindowFields, its window would run over every interval before the page
+        // when there's no start bound. The page comes back in time order off the unique
+        // index, every interval is compared with its neighbour and the oldest one with
+        // the interval looked up right before it
+        let query = HistoryQuery {
+            units: ValueRange::default(),
+            count: ValueRange::default(),
+            sort_field: SortField::StartTime,
+            ..query.clone()
         };
+        let collection = self.collection();
+        let mut filter = filter_document(&query);
+        if let Some(cursor) = &query.after {
+            filter.insert("$and", vec![after_cursor_document(cursor)]);
+        }
 
-        let exists = collection.find_one(filter.clone()).await?;
+        let find_options = FindOptions::builder()
+            .sort(sort_document(&query))
+            .skip(query.offset as u64)
+            .limit(query.limit as i64)
+            .build();
+        let mut cursor = collection.find(filter).with_options(find_options).await?;
+        let mut intervals = Vec::new();
+        while let Some(doc) = cursor.try_next().await? {
+            intervals.push(interval_from_document(&doc)?);
+        }
+        if query.sort_order == SortOrder::Desc {
+            intervals.reverse();
+        }
 
-        if exists.is_none() {
-            let doc = doc! {
-                "start_time": interval.start_time,
-                "end_time": interval.end_time,
-                "count": interval.count as i64,
-                "units": interval.units as i64,
-                "created_at": Utc::now()
-            };
+        let mut previous = match intervals.first() {
+            Some(oldest) => collection
+                .find_one(doc! {
+                    "$or": [
+                        { "start_time": { "$lt": oldest.start_time } },
+                        { "start_time": oldest.start_time, "end_time": { "$lt": oldest.end_time } }
+                    ]
+                })
+                .sort(doc! { "start_time": -1, "end_time": -1 })
+                .await?
+                .map(|doc| interval_from_document(&doc))
+                .transpose()?,
+            None => None,
+        };
 
-            collection.insert_one(doc).await?;
-            stored_count += 1;
+        let mut results: Vec<RunepoolUnitsDelta> = intervals
+            .into_iter()
+            .map(|interval| {
+                let before = previous.replace(interval.clone());
+                RunepoolUnitsDelta::new(
+                    interval,
+                    before.as_ref().map(|before| before.units),
+                    before.as_ref().map(|before| before.count),
+                )
+            })
+            .collect();
+        if query.sort_order == SortOrder::Desc {
+            results.reverse();
         }
+
+        metrics.set_record_count(results.len());
+        metrics.finish();
+        Ok(results)
     }
 
-    metrics.finish();
-    Ok(stored_count)
+    async fn meta(&self, query: &HistoryQuery) -> Result<Option<MetaStats>> {
+        let metrics = OperationMetrics::new(
+            DatabaseType::MongoDB,
+            DatabaseOperation::Read,
+            2,
+            "runepool units (meta)".to_string(),
+        );
+
+        // The earliest and the latest match, each one read off the unique time index
+        let collection = self.collection();
+        let find_edge = |direction: i32| {
+            collection.find_one(filter_document(query)).with_options(
+                FindOneOptions::builder()
+                    .sort(doc! { "start_time": direction, "end_time": direction })
+                    .build(),
+            )
+        };
+        let (first, last) = tokio::try_join!(find_edge(1), find_edge(-1))?;
+
+        let meta = match (first, last) {
+            (Some(first), Some(last)) => Some(MetaStats::new(
+                &interval_from_document(&first)?,
+                &interval_from_document(&last)?,
+            )),
+            _ => None,
+        };
+
+        metrics.finish();
+        Ok(meta)
+    }
+
+    async fn stats(&self, query: &HistoryQuery) -> Result<Option<RunepoolUnitsStats>> {
+        let mut metrics = OperationMetrics::new(
+            DatabaseType::MongoDB,
+            DatabaseOperation::Stats,
+            0,
+            "runepool units".to_string(),
+        );
+
+        let mut group = doc! {
+            "_id": null,
+            "intervals": { "$sum": 1_i64 },
+            "start_time": { "$first": "$start_time" },
+            "end_time": { "$last": "$end_time" }
+        };
+        stats_accumulators(&mut group, "units");
+        stats_accumulators(&mut group, "count");
+        let pipeline = vec![
+            doc! { "$match": filter_document(query) },
+            doc! { "$sort": { "start_time": 1, "end_time": 1 } },
+            doc! { "$group": group },
+        ];
+
+        // No document comes out of $group when nothing matches
+        let mut cursor = self.collection().aggregate(pipeline).await?;
+        let stats = match cursor.try_next().await? {
+            Some(doc) => Some(RunepoolUnitsStats {
+                start_time: doc.get_datetime("start_time")?.to_chrono(),
+                end_time: doc.get_datetime("end_time")?.to_chrono(),
+                intervals: doc.get_i64("intervals")? as u64,
+                units: field_stats(&doc, "units")?,
+                count: field_stats(&doc, "count")?,
+            }),
+            None => None,
+        };
+
+        metrics.set_record_count(stats.as_ref().map_or(0, |stats| stats.intervals as usize));
+        metrics.finish();
+        Ok(stats)
+    }
+
+    async fn gaps(&self, query: &HistoryQuery) -> Result<Option<RunepoolUnitsGaps>> {
+        let mut metrics = OperationMetrics::new(
+            DatabaseType::MongoDB,
+            DatabaseOperation::Read,
+            0,
+            "runepool unit gaps".to_string(),
+        );
+
+        let filter = filter_document(&HistoryQuery {
+            units: ValueRange::default(),
+            count: ValueRange::default(),
+            ..query.clone()
+        });
+        // $shift puts the times of the interval before on each one, the span of the range
+        // and the pairs that don't meet come out of one $facet
+        let pipeline = vec![
+            doc! { "$match": filter },
+            doc! { "$setWindowFields": {
+                "sortBy": { "start_time": 1, "end_time": 1 },
+                "output": {
+                    "previous_start": { "$shift": { "output": "$start_time", "by": -1 } },
+                    "previous_end": { "$shift": { "output": "$end_time", "by": -1 } }
+                }
+            } },
+            doc! { "$sort": { "start_time": 1, "end_time": 1 } },
+            doc! { "$facet": {
+                "span": [
+                    { "$group": {
+                        "_id": null,
+                        "intervals": { "$sum": 1_i64 },
+                        "start_time": { "$first": "$start_time" },
+                        "end_time": { "$last": "$end_time" }
+                    } }
+                ],
+                "breaks": [
+                    { "$match": {
+                        "previous_end": { "$ne": null },
+                        "$expr": { "$or": [
+                            { "$ne": ["$previous_end", "$start_time"] },
+                            { "$eq": ["$previous_start", "$start_time"] }
+                        ] }
+                    } },
+                    { "$project": {
+                        "_id": 0,
+                        "previous_start": 1,
+                        "previous_end": 1,
+                        "start_time": 1,
+                        "end_time": 1
+                    } }
+                ]
+            } },
+        ];
+
+        let mut cursor = self.collection().aggregate(pipeline).await?;
+        let gaps = match cursor.try_next().await? {
+            Some(doc) => match doc.get_array("span")?.first() {
+                // Nothing matched, $group gave no document
+                None => None,
+                Some(span) => {
+                    let span = span
+                        .as_document()
+                        .ok_or_else(|| anyhow::anyhow!("Malformed gaps span"))?;
+                    let breaks = doc
+                        .get_array("breaks")?
+                        .iter()
+                        .map(|gap| {
+                            let gap = gap
+                                .as_document()
+                                .ok_or_else(|| anyhow::anyhow!("Malformed gaps break"))?;
+                            Ok(IntervalBreak {
+                                previous_start: gap.get_datetime("previous_start")?.to_chrono(),
+                                previous_end: gap.get_datetime("previous_end")?.to_chrono(),
+                                start_time: gap.get_datetime("start_time")?.to_chrono(),
+                                end_time: gap.get_datetime("end_time")?.to_chrono(),
+                            })
+                        })
+                        .collect::<Result<Vec<_>>>()?;
+                    Some(RunepoolUnitsGaps::new(
+                        span.get_datetime("start_time")?.to_chrono(),
+                        span.get_datetime("end_time")?.to_chrono(),
+                        span.get_i64("intervals")? as u64,
+                        breaks,
+                    ))
+                }
+            },
+            None => None,
+        };
+
+        metrics.set_record_count(gaps.as_ref().map_or(0, |gaps| gaps.intervals as usize));
+        metrics.finish();
+        Ok(gaps)
+    }
+
+    async fn count(&self, query: &HistoryQuery) -> Result<u64> {
+        let mut metrics = OperationMetrics::new(
+            DatabaseType::MongoDB,
+            DatabaseOperation::Count,
+            0,
+            "runepool units".to_string(),
+        );
+
+        let count = self
+            .collection()
+            .count_documents(filter_document(query))
+            .await?;
+
+        metrics.set_record_count(count as usize);
+        metrics.finish();
+        Ok(count)
+    }
+
+    async fn delete_range(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<usize> {
+        let filter = doc! {
+            "start_time": { "$gte": start },
+            "end_time": { "$lte": end }
+        };
+
+        let res = self.collection().delete_many(filter).await?;
+        Ok(res.deleted_count as usize)
+    }
+
+    async fn health(&self) -> Result<()> {
+        self.client
+            .database("admin")
+            .run_command(doc! {"ping": 1})
+            .await?;
+        Ok(())
+    }
 }
@@ -1,43 +1,864 @@
-use crate::core::models::runepool_units_history::RunepoolUnitsInterval;
-use crate::utils::metrics::{DatabaseOperation, DatabaseType, OperationMetrics};
+use super::{stored_int, HistoryQuery, Resample, RunepoolStore};
+use crate::core::models::common::{CountMode, Interval, SortField, SortOrder, ValueRange};
+use crate::core::models::runepool_units_history::{
+    FieldStats, IntervalBreak, MetaStats, RunepoolUnitsDelta, RunepoolUnitsGaps,
+    RunepoolUnitsInterval, RunepoolUnitsStats,
+};
+use crate::utils::metrics::{
+    log_db_operation_metrics, DatabaseOperation, DatabaseType, OperationMetrics,
+};
+use anyhow::Result;
 use chrono::{DateTime, Utc};
 use sqlx::postgres::PgPool;
 use sqlx::types::time::OffsetDateTime;
+use std::time::Instant;
 
 pub fn convert_datetime(dt: DateTime<Utc>) -> OffsetDateTime {
     OffsetDateTime::from_unix_timestamp(dt.timestamp()).expect("Valid timestamp")
 }
 
-pub async fn store_postgres_intervals(
-    pool: &PgPool,
-    intervals: &[RunepoolUnitsInterval],
-) -> sqlx::Result<usize> {
-    let metrics = OperationMetrics::new(
-        DatabaseType::Postgres,
-        DatabaseOperation::Write,
-        intervals.len(),
-        "runepool units".to_string(),
-    );
+pub fn convert_offset_datetime(dt: OffsetDateTime) -> DateTime<Utc> {
+    DateTime::from_timestamp(dt.unix_timestamp(), 0).expect("Valid timestamp")
+}
+
+// Row as stored in the runepool_unit_intervals table
+struct PgRunepoolUnitsInterval {
+    start_time: OffsetDateTime,
+    end_time: OffsetDateTime,
+    count: i64,
+    units: i64,
+}
+
+impl From<PgRunepoolUnitsInterval> for RunepoolUnitsInterval {
+    fn from(row: PgRunepoolUnitsInterval) -> Self {
+        Self {
+            start_time: convert_offset_datetime(row.start_time),
+            end_time: convert_offset_datetime(row.end_time),
+            count: row.count as u64,
+            units: row.units as u64,
+        }
+    }
+}
+
+// Row with the values of the row stored right before it
+struct PgRunepoolUnitsDelta {
+    start_time: OffsetDateTime,
+    end_time: OffsetDateTime,
+    count: i64,
+    units: i64,
+    previous_count: Option<i64>,
+    previous_units: Option<i64>,
+}
+
+impl From<PgRunepoolUnitsDelta> for RunepoolUnitsDelta {
+    fn from(row: PgRunepoolUnitsDelta) -> Self {
+        let interval = RunepoolUnitsInterval {
+            start_time: convert_offset_datetime(row.start_time),
+            end_time: convert_offset_datetime(row.end_time),
+            count: row.count as u64,
+            units: row.units as u64,
+        };
+        Self::new(
+            interval,
+            row.previous_units.map(|units| units as u64),
+            row.previous_count.map(|count| count as u64),
+        )
+    }
+}
+
+// Earliest and latest matching rows side by side
+struct PgIntervalBreak {
+    previous_start: OffsetDateTime,
+    previous_end: OffsetDateTime,
+    start_time: OffsetDateTime,
+    end_time: OffsetDateTime,
+}
+
+impl From<PgIntervalBreak> for IntervalBreak {
+    fn from(row: PgIntervalBreak) -> Self {
+        Self {
+            previous_start: convert_offset_datetime(row.previous_start),
+            previous_end: convert_offset_datetime(row.previous_end),
+            start_time: convert_offset_datetime(row.start_time),
+            end_time: convert_offset_datetime(row.end_time),
+        }
+    }
+}
+
+struct PgMetaStats {
+    start_time: OffsetDateTime,
+    end_time: OffsetDateTime,
+    start_count: i64,
+    end_count: i64,
+    start_units: i64,
+    end_units: i64,
+}
+
+impl From<PgMetaStats> for MetaStats {
+    fn from(row: PgMetaStats) -> Self {
+        Self {
+            start_time: convert_offset_datetime(row.start_time),
+            end_time: convert_offset_datetime(row.end_time),
+            start_count: row.start_count as u64,
+            end_count: row.end_count as u64,
+            start_units: row.start_units as u64,
+            end_units: row.end_units as u64,
+        }
+    }
+}
+
+// Aggregates of the stats query, every one of them is NULL when no row matches
+struct PgRunepoolUnitsStats {
+    intervals: i64,
+    start_time: Option<OffsetDateTime>,
+    end_time: Option<OffsetDateTime>,
+    units_min: Option<i64>,
+    units_max: Option<i64>,
+    units_avg: Option<f64>,
+    units_stddev: Option<f64>,
+    units_first: Option<i64>,
+    units_last: Option<i64>,
+    count_min: Option<i64>,
+    count_max: Option<i64>,
+    count_avg: Option<f64>,
+    count_stddev: Option<f64>,
+    count_first: Option<i64>,
+    count_last: Option<i64>,
+}
+
+impl PgRunepoolUnitsStats {
+    fn into_stats(self) -> Option<RunepoolUnitsStats> {
+        Some(RunepoolUnitsStats {
+            start_time: convert_offset_datetime(self.start_time?),
+            end_time: convert_offset_datetime(self.end_time?),
+            intervals: self.intervals as u64,
+            units: FieldStats::new(
+                self.units_min? as u64,
+                self.units_max? as u64,
+                self.units_avg?,
+                self.units_stddev?,
+                self.units_first? as u64,
+                self.units_last? as u64,
+            ),
+            count: FieldStats::new(
+                self.count_min? as u64,
+                self.count_max? as u64,
+                self.count_avg?,
+                self.count_stddev?,
+                self.count_first? as u64,
+                self.count_last? as u64,
+            ),
+        })
+    }
+}
+
+// query_as! only takes literals, so every ORDER BY and its keyset condition
+// ($9 cursor start time, $10 cursor value when sorting by a value) gets its own checked query
+macro_rules! select_intervals {
+    ($pool:expr, $query:expr, $order_by:tt, $after:tt, $($after_param:expr),+) => {{
+        let (start, end) = time_range_params($query);
+        let (units_min, units_max) = value_range_params($query.units);
+        let (count_min, count_max) = value_range_params($query.count);
+        sqlx::query_as!(
+            PgRunepoolUnitsInterval,
+            "SELECT start_time, end_time, count, units FROM runepool_unit_intervals
+             WHERE ($1::timestamptz IS NULL OR start_time >= $1)
+               AND ($2::timestamptz IS NULL OR end_time <= $2)
+               AND ($3::bigint IS NULL OR units >= $3)
+               AND ($4::bigint IS NULL OR units <= $4)
+               AND ($5::bigint IS NULL OR count >= $5)
+               AND ($6::bigint IS NULL OR count <= $6)
+               AND ($9::timestamptz IS NULL OR "
+                + $after
+                + ")
+             ORDER BY "
+                + $order_by
+                + " LIMIT $7 OFFSET $8",
+            start,
+            end,
+            units_min,
+            units_max,
+            count_min,
+            count_max,
+            $query.limit as i64,
+            $query.offset as i64,
+            $($after_param),+
+        )
+        .fetch_all(&$pool)
+        .await?
+    }};
+}
+
+// Deltas: the page is read first and each row looks up the one before it on the
+// primary key, so no window runs over the rows before the page. $5 is the cursor start
+// time
+macro_rules! select_deltas {
+    ($pool:expr, $query:expr, $order_by:tt, $after:tt) => {{
+        let (start, end) = time_range_params($query);
+        sqlx::query_as!(
+            PgRunepoolUnitsDelta,
+            r#"SELECT start_time AS "start_time!", end_time AS "end_time!",
+                      page.count AS "count!", page.units AS "units!",
+                      previous.count AS "previous_count?", previous.units AS "previous_units?"
+             FROM (
+                 SELECT start_time, end_time, count, units FROM runepool_unit_intervals
+                 WHERE ($1::timestamptz IS NULL OR start_time >= $1)
+                   AND ($2::timestamptz IS NULL OR end_time <= $2)
+                   AND ($5::timestamptz IS NULL OR "#
+                + $after
+                + ")
+                 ORDER BY "
+                + $order_by
+                + " LIMIT $3 OFFSET $4
+             ) AS page
+             LEFT JOIN LATERAL (
+                 SELECT count, units FROM runepool_unit_intervals
+                 WHERE (start_time, end_time) < (page.start_time, page.end_time)
+                 ORDER BY start_time DESC, end_time DESC LIMIT 1
+             ) AS previous ON true
+             ORDER BY "
+                + $order_by,
+            start,
+            end,
+            $query.limit as i64,
+            $query.offset as i64,
+            $query
+                .after
+                .map(|cursor| convert_datetime(cursor.start_time))
+        )
+        .fetch_all(&$pool)
+        .await?
+    }};
+}
+
+// Resample buckets: rows are truncated to their bucket ($9 date_trunc field, 5min
+// goes through date_bin), units and count are taken from the latest row of the
+// bucket unless $11 asks for summed counts. $10 is the bucket width
+macro_rules! select_buckets {
+    ($pool:expr, $query:expr, $resample:expr, $order_by:tt) => {{
+        let (start, end) = time_range_params($query);
+        let (units_min, units_max) = value_range_params($query.units);
+        let (count_min, count_max) = value_range_params($query.count);
+        let (field, width) = bucket_params($resample.interval);
+        sqlx::query_as!(
+            PgRunepoolUnitsInterval,
+            r#"SELECT start_time AS "start_time!", end_time AS "end_time!",
+                      count AS "count!", units AS "units!"
+             FROM (
+                 SELECT bucket AS start_time,
+                        bucket + $10::text::interval AS end_time,
+                        CASE WHEN $11 THEN SUM(count)::bigint
+                             ELSE (ARRAY_AGG(count ORDER BY start_time DESC))[1] END AS count,
+                        (ARRAY_AGG(units ORDER BY start_time DESC))[1] AS units
+                 FROM (
+                     SELECT CASE WHEN $9::text = '5min'
+                                 THEN date_bin('5 minutes', start_time, TIMESTAMPTZ 'epoch')
+                                 ELSE date_trunc($9, start_time, 'UTC') END AS bucket,
+                            start_time, count, units
+                     FROM runepool_unit_intervals
+                     WHERE ($1::timestamptz IS NULL OR start_time >= $1)
+                       AND ($2::timestamptz IS NULL OR end_time <= $2)
+                       AND ($3::bigint IS NULL OR units >= $3)
+                       AND ($4::bigint IS NULL OR units <= $4)
+                       AND ($5::bigint IS NULL OR count >= $5)
+                       AND ($6::bigint IS NULL OR count <= $6)
+                 ) AS intervals
+                 GROUP BY bucket
+             ) AS buckets
+             ORDER BY "#
+                + $order_by
+                + " LIMIT $7 OFFSET $8",
+            start,
+            end,
+            units_min,
+            units_max,
+            count_min,
+            count_max,
+            $query.limit as i64,
+            $query.offset as i64,
+            field,
+            width,
+            $resample.count_mode == CountMode::Sum
+        )
+        .fetch_all(&$pool)
+        .await?
+    }};
+}
+
+// date_trunc field and interval width of a bucket
+fn bucket_params(interval: Interval) -> (&'static str, &'static str) {
+    match interval {
+        Interval::FiveMin => ("5min", "5 minutes"),
+        Interval::Hour => ("hour", "1 hour"),
+        Interval::Day => ("day", "1 day"),
+        Interval::Week => ("week", "1 week"),
+        Interval::Month => ("month", "1 month"),
+        Interval::Quarter => ("quarter", "3 months"),
+        Interval::Year => ("year", "1 year"),
+    }
+}
+
+fn time_range_params(query: &HistoryQuery) -> (Option<OffsetDateTime>, Option<OffsetDateTime>) {
+    (
+        query.start_time.map(convert_datetime),
+        query.end_time.map(convert_datetime),
+    )
+}
+
+fn value_range_params(range: ValueRange) -> (Option<i64>, Option<i64>) {
+    (range.min.map(stored_int), range.max.map(stored_int))
+}
+
+fn cursor_params(query: &HistoryQuery) -> (Option<OffsetDateTime>, Option<i64>) {
+    match query.after {
+        Some(cursor) => (
+            Some(convert_datetime(cursor.start_time)),
+            Some(stored_int(cursor.value)),
+        ),
+        None => (None, None),
+    }
+}
+
+// How intervals get written, picked with POSTGRES_WRITE_MODE so per-row and bulk
+// ingestion can be compared on the same data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PostgresWriteMode {
+    // One INSERT round trip per interval
+    Row,
+    // A single multi-row INSERT from UNNEST'ed arrays
+    Unnest,
+    // Binary COPY into a staging table, then merged into the real one
+    Copy,
+}
+
+impl PostgresWriteMode {
+    pub fn from_env() -> Self {
+        match std::env::var("POSTGRES_WRITE_MODE")
+            .unwrap_or_default()
+            .to_lowercase()
+            .as_str()
+        {
+            "unnest" => PostgresWriteMode::Unnest,
+            "copy" => PostgresWriteMode::Copy,
+            _ => PostgresWriteMode::Row,
+        }
+    }
+}
+
+impl std::fmt::Display for PostgresWriteMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mode_str = match self {
+            PostgresWriteMode::Row => "row",
+            PostgresWriteMode::Unnest => "unnest",
+            PostgresWriteMode::Copy => "copy",
+        };
+        write!(f, "{}", mode_str)
+    }
+}
+
+// Microseconds between the unix epoch and the postgres epoch (2000-01-01)
+const PG_EPOCH_OFFSET_MICROS: i64 = 946_684_800_000_000;
+
+// Encodes the intervals in the COPY binary format (header, one tuple per interval, trailer)
+fn encode_copy_binary(intervals: &[RunepoolUnitsInterval]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(19 + intervals.len() * 50 + 2);
+    buf.extend_from_slice(b"PGCOPY\n\xff\r\n\0");
+    buf.extend_from_slice(&0i32.to_be_bytes()); // flags
+    buf.extend_from_slice(&0i32.to_be_bytes()); // header extension length
 
-    let mut stored_count = 0;
     for interval in intervals {
+        buf.extend_from_slice(&4i16.to_be_bytes());
+        for value in [
+            interval.start_time.timestamp() * 1_000_000 - PG_EPOCH_OFFSET_MICROS,
+            interval.end_time.timestamp() * 1_000_000 - PG_EPOCH_OFFSET_MICROS,
+            interval.count as i64,
+            interval.units as i64,
+        ] {
+            buf.extend_from_slice(&8i32.to_be_bytes());
+            buf.extend_from_slice(&value.to_be_bytes());
+        }
+    }
+
+    buf.extend_from_slice(&(-1i16).to_be_bytes());
+    buf
+}
+
+#[derive(Clone)]
+pub struct PostgresStore {
+    pool: PgPool,
+    write_mode: PostgresWriteMode,
+}
+
+impl PostgresStore {
+    pub fn new(pool: PgPool) -> Self {
+        Self {
+            pool,
+            write_mode: PostgresWriteMode::from_env(),
+        }
+    }
+
+    pub fn with_write_mode(mut self, write_mode: PostgresWriteMode) -> Self {
+        self.write_mode = write_mode;
+        self
+    }
+
+    async fn insert_rows(&self, intervals: &[RunepoolUnitsInterval]) -> Result<usize> {
+        let mut stored_count = 0;
+        for interval in intervals {
+            let res = sqlx::query!(
+                "INSERT INTO runepool_unit_intervals (start_time, end_time, count, units)
+             VALUES ($1, $2, $3, $4)
+             ON CONFLICT (start_time, end_time) DO NOTHING",
+                convert_datetime(interval.start_time),
+                convert_datetime(interval.end_time),
+                interval.count as i64,
+                interval.units as i64
+            )
+            .execute(&self.pool)
+            .await?;
+
+            if res.rows_affected() > 0 {
+                stored_count += 1;
+            }
+        }
+
+        Ok(stored_count)
+    }
+
+    async fn insert_unnest(&self, intervals: &[RunepoolUnitsInterval]) -> Result<usize> {
+        let start_times: Vec<OffsetDateTime> = intervals
+            .iter()
+            .map(|interval| convert_datetime(interval.start_time))
+            .collect();
+        let end_times: Vec<OffsetDateTime> = intervals
+            .iter()
+            .map(|interval| convert_datetime(interval.end_time))
+            .collect();
+        let counts: Vec<i64> = intervals
+            .iter()
+            .map(|interval| interval.count as i64)
+            .collect();
+        let units: Vec<i64> = intervals
+            .iter()
+            .map(|interval| interval.units as i64)
+            .collect();
+
         let res = sqlx::query!(
             "INSERT INTO runepool_unit_intervals (start_time, end_time, count, units)
-             VALUES ($1, $2, $3, $4)
+             SELECT * FROM UNNEST($1::timestamptz[], $2::timestamptz[], $3::bigint[], $4::bigint[])
              ON CONFLICT (start_time, end_time) DO NOTHING",
-            convert_datetime(interval.start_time),
-            convert_datetime(interval.end_time),
-            interval.count as i64,
-            interval.units as i64
+            &start_times,
+            &end_times,
+            &counts,
+            &units
         )
-        .execute(pool)
+        .execute(&self.pool)
         .await?;
 
-        if res.rows_affected() > 0 {
-            stored_count += 1;
-        }
+        Ok(res.rows_affected() as usize)
+    }
+
+    async fn insert_copy(&self, intervals: &[RunepoolUnitsInterval]) -> Result<usize> {
+        let mut tx = self.pool.begin().await?;
+
+        // The staging table only lives for this transaction, so the queries touching it
+        // can't be checked at compile time
+        sqlx::query(
+            "CREATE TEMP TABLE runepool_unit_intervals_staging (
+                start_time TIMESTAMPTZ NOT NULL,
+                end_time TIMESTAMPTZ NOT NULL,
+                count BIGINT NOT NULL,
+                units BIGINT NOT NULL
+             ) ON COMMIT DROP",
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        let mut copy = tx
+            .copy_in_raw(
+                "COPY runepool_unit_intervals_staging (start_time, end_time, count, units)
+                 FROM STDIN (FORMAT binary)",
+            )
+            .await?;
+        copy.send(encode_copy_binary(intervals)).await?;
+        copy.finish().await?;
+
+        let res = sqlx::query(
+            "INSERT INTO runepool_unit_intervals (start_time, end_time, count, units)
+             SELECT start_time, end_time, count, units FROM runepool_unit_intervals_staging
+             ON CONFLICT (start_time, end_time) DO NOTHING",
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(res.rows_affected() as usize)
+    }
+}
+
+impl RunepoolStore for PostgresStore {
+    const DATABASE_TYPE: DatabaseType = DatabaseType::Postgres;
+
+    async fn insert(&self, intervals: &[RunepoolUnitsInterval]) -> Result<usize> {
+        let metrics = OperationMetrics::new(
+            DatabaseType::Postgres,
+            DatabaseOperation::Write,
+            intervals.len(),
+            format!("runepool units ({} mode)", self.write_mode),
+        );
+
+        let stored_count = match self.write_mode {
+            PostgresWriteMode::Row => self.insert_rows(intervals).await?,
+            PostgresWriteMode::Unnest => self.insert_unnest(intervals).await?,
+            PostgresWriteMode::Copy => self.insert_copy(intervals).await?,
+        };
+
+        metrics.finish();
+        Ok(stored_count)
+    }
+
+    async fn query(&self, query: &HistoryQuery) -> Result<Vec<RunepoolUnitsInterval>> {
+        let metrics = OperationMetrics::new(
+            DatabaseType::Postgres,
+            DatabaseOperation::Read,
+            query.limit as usize,
+            "runepool units".to_string(),
+        );
+
+        let start_time = Instant::now();
+        let (after_start, after_value) = cursor_params(query);
+        let rows = match (query.sort_field, query.sort_order) {
+            (SortField::StartTime, SortOrder::Asc) => {
+                select_intervals!(
+                    self.pool,
+                    query,
+                    "start_time ASC, end_time ASC",
+                    "start_time > $9",
+                    after_start
+                )
+            }
+            (SortField::StartTime, SortOrder::Desc) => {
+                select_intervals!(
+                    self.pool,
+                    query,
+                    "start_time DESC, end_time DESC",
+                    "start_time < $9",
+                    after_start
+                )
+            }
+            (SortField::Units, SortOrder::Asc) => select_intervals!(
+                self.pool,
+                query,
+                "units ASC, start_time ASC, end_time ASC",
+                "(units, start_time) > ($10::bigint, $9)",
+                after_start,
+                after_value
+            ),
+            (SortField::Units, SortOrder::Desc) => select_intervals!(
+                self.pool,
+                query,
+                "units DESC, start_time ASC, end_time ASC",
+                "units < $10::bigint OR (units = $10 AND start_time > $9)",
+                after_start,
+                after_value
+            ),
+            (SortField::Count, SortOrder::Asc) => select_intervals!(
+                self.pool,
+                query,
+                "count ASC, start_time ASC, end_time ASC",
+                "(count, start_time) > ($10::bigint, $9)",
+                after_start,
+                after_value
+            ),
+            (SortField::Count, SortOrder::Desc) => select_intervals!(
+                self.pool,
+                query,
+                "count DESC, start_time ASC, end_time ASC",
+                "count < $10::bigint OR (count = $10 AND start_time > $9)",
+                after_start,
+                after_value
+            ),
+        };
+        let result: Vec<RunepoolUnitsInterval> = rows.into_iter().map(Into::into).collect();
+        log_db_operation_metrics(
+            &format!("read_intervals_{}_records", result.len()),
+            start_time,
+        );
+
+        metrics.finish();
+        Ok(result)
+    }
+
+    async fn resample(
+        &self,
+        query: &HistoryQuery,
+        resample: Resample,
+    ) -> Result<Vec<RunepoolUnitsInterval>> {
+        let mut metrics = OperationMetrics::new(
+            DatabaseType::Postgres,
+            DatabaseOperation::Aggregate,
+            0,
+            format!("runepool units (by {})", resample.interval),
+        );
+
+        let rows = match (query.sort_field, query.sort_order) {
+            (SortField::StartTime, SortOrder::Asc) => {
+                select_buckets!(self.pool, query, resample, "start_time ASC")
+            }
+            (SortField::StartTime, SortOrder::Desc) => {
+                select_buckets!(self.pool, query, resample, "start_time DESC")
+            }
+            (SortField::Units, SortOrder::Asc) => {
+                select_buckets!(self.pool, query, resample, "units ASC, start_time ASC")
+            }
+            (SortField::Units, SortOrder::Desc) => {
+                select_buckets!(self.pool, query, resample, "units DESC, start_time ASC")
+            }
+            (SortField::Count, SortOrder::Asc) => {
+                select_buckets!(self.pool, query, resample, "count ASC, start_time ASC")
+            }
+            (SortField::Count, SortOrder::Desc) => {
+                select_buckets!(self.pool, query, resample, "count DESC, start_time ASC")
+            }
+        };
+        let result: Vec<RunepoolUnitsInterval> = rows.into_iter().map(Into::into).collect();
+
+        metrics.set_record_count(result.len());
+        metrics.finish();
+        Ok(result)
+    }
+
+    async fn deltas(&self, query: &HistoryQuery) -> Result<Vec<RunepoolUnitsDelta>> {
+        let mut metrics = OperationMetrics::new(
+            DatabaseType::Postgres,
+            DatabaseOperation::Read,
+            0,
+            "runepool unit deltas".to_string(),
+        );
+
+        let rows = match query.sort_order {
+            SortOrder::Asc => select_deltas!(
+                self.pool,
+                query,
+                "start_time ASC, end_time ASC",
+                "start_time > $5"
+            ),
+            SortOrder::Desc => select_deltas!(
+                self.pool,
+                query,
+                "start_time DESC, end_time DESC",
+                "start_time < $5"
+            ),
+        };
+        let results: Vec<RunepoolUnitsDelta> = rows.into_iter().map(Into::into).collect();
+
+        metrics.set_record_count(results.len());
+        metrics.finish();
+        Ok(results)
+    }
+
+    async fn meta(&self, query: &HistoryQuery) -> Result<Option<MetaStats>> {
+        let metrics = OperationMetrics::new(
+            DatabaseType::Postgres,
+            DatabaseOperation::Read,
+            2,
+            "runepool units (meta)".to_string(),
+        );
+        let (start, end) = time_range_params(query);
+        let (units_min, units_max) = value_range_params(query.units);
+        let (count_min, count_max) = value_range_params(query.count);
+
+        // Two index ordered LIMIT 1 reads (the CTE is inlined into both), no row when
+        // nothing matches
+        let row = sqlx::query_as!(
+            PgMetaStats,
+            r#"WITH intervals AS NOT MATERIALIZED (
+                 SELECT start_time, end_time, count, units FROM runepool_unit_intervals
+                 WHERE ($1::timestamptz IS NULL OR start_time >= $1)
+                   AND ($2::timestamptz IS NULL OR end_time <= $2)
+                   AND ($3::bigint IS NULL OR units >= $3)
+                   AND ($4::bigint IS NULL OR units <= $4)
+                   AND ($5::bigint IS NULL OR count >= $5)
+                   AND ($6::bigint IS NULL OR count <= $6)
+             )
+             SELECT first.start_time AS "start_time!", last.end_time AS "end_time!",
+                    first.count AS "start_count!", last.count AS "end_count!",
+                    first.units AS "start_units!", last.units AS "end_units!"
+             FROM (SELECT * FROM intervals ORDER BY start_time ASC, end_time ASC LIMIT 1) AS first
+             CROSS JOIN (SELECT * FROM intervals ORDER BY start_time DESC, end_time DESC LIMIT 1) AS last"#,
+            start,
+            end,
+            units_min,
+            units_max,
+            count_min,
+            count_max
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        metrics.finish();
+        Ok(row.map(Into::into))
     }
 
-    metrics.finish();
-    Ok(stored_count)
+    async fn stats(&self, query: &HistoryQuery) -> Result<Option<RunepoolUnitsStats>> {
+        let mut metrics = OperationMetrics::new(
+            DatabaseType::Postgres,
+            DatabaseOperation::Stats,
+            0,
+            "runepool units".to_string(),
+        );
+        let (start, end) = time_range_params(query);
+        let (units_min, units_max) = value_range_params(query.units);
+        let (count_min, count_max) = value_range_params(query.count);
+
+        // One pass of aggregates, first and last go by start time then end time
+        let row = sqlx::query_as!(
+            PgRunepoolUnitsStats,
+            r#"SELECT COUNT(*) AS "intervals!",
+                      MIN(start_time) AS start_time,
+                      (ARRAY_AGG(end_time ORDER BY start_time DESC, end_time DESC))[1] AS end_time,
+                      MIN(units) AS units_min,
+                      MAX(units) AS units_max,
+                      AVG(units)::float8 AS units_avg,
+                      CASE WHEN COUNT(*) > 0 THEN COALESCE(STDDEV_SAMP(units), 0)::float8 END AS units_stddev,
+                      (ARRAY_AGG(units ORDER BY start_time ASC, end_time ASC))[1] AS units_first,
+                      (ARRAY_AGG(units ORDER BY start_time DESC, end_time DESC))[1] AS units_last,
+                      MIN(count) AS count_min,
+                      MAX(count) AS count_max,
+                      AVG(count)::float8 AS count_avg,
+                      CASE WHEN COUNT(*) > 0 THEN COALESCE(STDDEV_SAMP(count), 0)::float8 END AS count_stddev,
+                      (ARRAY_AGG(count ORDER BY start_time ASC, end_time ASC))[1] AS count_first,
+                      (ARRAY_AGG(count ORDER BY start_time DESC, end_time DESC))[1] AS count_last
+             FROM runepool_unit_intervals
+             WHERE ($1::timestamptz IS NULL OR start_time >= $1)
+               AND ($2::timestamptz IS NULL OR end_time <= $2)
+               AND ($3::bigint IS NULL OR units >= $3)
+               AND ($4::bigint IS NULL OR units <= $4)
+               AND ($5::bigint IS NULL OR count >= $5)
+               AND ($6::bigint IS NULL OR count <= $6)"#,
+            start,
+            end,
+            units_min,
+            units_max,
+            count_min,
+            count_max
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        metrics.set_record_count(row.intervals as usize);
+        metrics.finish();
+        Ok(row.into_stats())
+    }
+
+    async fn gaps(&self, query: &HistoryQuery) -> Result<Option<RunepoolUnitsGaps>> {
+        let mut metrics = OperationMetrics::new(
+            DatabaseType::Postgres,
+            DatabaseOperation::Read,
+            0,
+            "runepool unit gaps".to_string(),
+        );
+        let (start, end) = time_range_params(query);
+
+        // Every interval is compared with the one before it by LAG, only the pairs that
+        // don't meet come back
+        let breaks = sqlx::query_as!(
+            PgIntervalBreak,
+            r#"SELECT previous_start AS "previous_start!", previous_end AS "previous_end!",
+                      start_time AS "start_time!", end_time AS "end_time!"
+             FROM (
+                 SELECT start_time, end_time,
+                        LAG(start_time) OVER previous AS previous_start,
+                        LAG(end_time) OVER previous AS previous_end
+                 FROM runepool_unit_intervals
+                 WHERE ($1::timestamptz IS NULL OR start_time >= $1)
+                   AND ($2::timestamptz IS NULL OR end_time <= $2)
+                 WINDOW previous AS (ORDER BY start_time, end_time)
+             ) AS neighbours
+             WHERE previous_end <> start_time OR previous_start = start_time
+             ORDER BY start_time, end_time"#,
+            start,
+            end
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let span = sqlx::query!(
+            r#"SELECT COUNT(*) AS "intervals!",
+                      MIN(start_time) AS start_time,
+                      (ARRAY_AGG(end_time ORDER BY start_time DESC, end_time DESC))[1] AS end_time
+             FROM runepool_unit_intervals
+             WHERE ($1::timestamptz IS NULL OR start_time >= $1)
+               AND ($2::timestamptz IS NULL OR end_time <= $2)"#,
+            start,
+            end
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        let gaps = match (span.start_time, span.end_time) {
+            (Some(start_time), Some(end_time)) => Some(RunepoolUnitsGaps::new(
+                convert_offset_datetime(start_time),
+                convert_offset_datetime(end_time),
+                span.intervals as u64,
+                breaks.into_iter().map(Into::into),
+            )),
+            _ => None,
+        };
+
+        metrics.set_record_count(gaps.as_ref().map_or(0, |gaps| gaps.intervals as usize));
+        metrics.finish();
+        Ok(gaps)
+    }
+
+    async fn count(&self, query: &HistoryQuery) -> Result<u64> {
+        let mut metrics = OperationMetrics::new(
+            DatabaseType::Postgres,
+            DatabaseOperation::Count,
+            0,
+            "runepool units".to_string(),
+        );
+        let (start, end) = time_range_params(query);
+        let (units_min, units_max) = value_range_params(query.units);
+        let (count_min, count_max) = value_range_params(query.count);
+
+        let count = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) AS "count!" FROM runepool_unit_intervals
+             WHERE ($1::timestamptz IS NULL OR start_time >= $1)
+               AND ($2::timestamptz IS NULL OR end_time <= $2)
+               AND ($3::bigint IS NULL OR units >= $3)
+               AND ($4::bigint IS NULL OR units <= $4)
+               AND ($5::bigint IS NULL OR count >= $5)
+               AND ($6::bigint IS NULL OR count <= $6)"#,
+            start,
+            end,
+            units_min,
+            units_max,
+            count_min,
+            count_max
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        metrics.set_record_count(count as usize);
+        metrics.finish();
+        Ok(count as u64)
+    }
+
+    async fn delete_range(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<usize> {
+        let res = sqlx::query!(
+            "DELETE FROM runepool_unit_intervals WHERE start_time >= $1 AND end_time <= $2",
+            convert_datetime(start),
+            convert_datetime(end)
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(res.rows_affected() as usize)
+    }
+
+    async fn health(&self) -> Result<()> {
+        sqlx::query("SELECT 1").fetch_one(&self.pool).await?;
+        Ok(())
+    }
 }
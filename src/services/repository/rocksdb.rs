@@ -1,37 +1,374 @@
-use crate::core::models::runepool_units_history::RunepoolUnitsInterval;
-use crate::utils::metrics::{DatabaseOperation, DatabaseType, OperationMetrics};
+use super::{env_flag, kv, GapsScan, HistoryQuery, Resample, RunepoolStore};
+use crate::core::models::runepool_units_history::{
+    MetaStats, RunepoolUnitsDelta, RunepoolUnitsGaps, RunepoolUnitsInterval, RunepoolUnitsStats,
+};
+use crate::utils::metrics::{
+    log_db_operation_metrics, DatabaseOperation, DatabaseType, OperationMetrics,
+};
 use anyhow::Result;
+use chrono::{DateTime, Utc};
+use std::collections::HashSet;
 use std::sync::Arc;
+use std::time::Instant;
+
+type KvEntry = (Box<[u8]>, RunepoolUnitsInterval);
+
+// Column family holding the units index, the default one holds the intervals
+pub const UNITS_INDEX_CF: &str = "runepool_units_by_units";
 
 // RocksDB Implementation
-pub async fn store_rocks_intervals(
+#[derive(Clone)]
+pub struct RocksStore {
     db: Arc<rocksdb::DB>,
-    intervals: Vec<RunepoolUnitsInterval>,
-) -> Result<(), anyhow::Error> {
-    let metrics = OperationMetrics::new(
-        DatabaseType::RocksDB,
-        DatabaseOperation::Write,
-        intervals.len(),
-        "runepool units".to_string(),
-    );
+    sync_writes: bool,
+}
+
+impl RocksStore {
+    pub fn new(db: Arc<rocksdb::DB>) -> Self {
+        Self {
+            db,
+            sync_writes: env_flag("ROCKSDB_SYNC_WRITES"),
+        }
+    }
+
+    // Whether every write batch is fsynced before the insert returns
+    pub fn with_sync_writes(mut self, sync_writes: bool) -> Self {
+        self.sync_writes = sync_writes;
+        self
+    }
+
+    fn units_index(&self) -> Result<&rocksdb::ColumnFamily> {
+        self.db
+            .cf_handle(UNITS_INDEX_CF)
+            .ok_or_else(|| anyhow::anyhow!("RocksDB column family {} is missing", UNITS_INDEX_CF))
+    }
+
+    fn write_options(&self) -> rocksdb::WriteOptions {
+        let mut write_options = rocksdb::WriteOptions::default();
+        write_options.set_sync(self.sync_writes);
+        write_options
+    }
+
+    // Raw entries of the range in key order (or reverse key order), bounded so rocksdb
+    // never reads keys outside of it
+    fn raw_entries(&self, range: &kv::KvRange) -> Result<rocksdb::DBIterator<'_>> {
+        let mut read_options = rocksdb::ReadOptions::default();
+        read_options.set_iterate_lower_bound(range.lower.clone());
+        read_options.set_iterate_upper_bound(range.upper.clone());
+        let mode = if range.reverse {
+            rocksdb::IteratorMode::End
+        } else {
+            rocksdb::IteratorMode::Start
+        };
+
+        Ok(match range.scan {
+            kv::KvScan::Time => self.db.iterator_opt(mode, read_options),
+            kv::KvScan::Units => self
+                .db
+                .iterator_cf_opt(self.units_index()?, read_options, mode),
+        })
+    }
+
+    fn entries(&self, range: &kv::KvRange) -> Result<impl Iterator<Item = Result<KvEntry>> + '_> {
+        Ok(self.raw_entries(range)?.map(|item| {
+            let (key, value) = item?;
+            Ok((key, serde_json::from_slice(&value)?))
+        }))
+    }
+
+    // First interval of the range that passes the filters
+    fn first_match(
+        &self,
+        range: &kv::KvRange,
+        query: &HistoryQuery,
+    ) -> Result<Option<RunepoolUnitsInterval>> {
+        for entry in self.entries(range)? {
+            let (_, interval) = entry?;
+            if query.matches(&interval) {
+                return Ok(Some(interval));
+            }
+        }
+        Ok(None)
+    }
+
+    // Indexes intervals stored before the units index existed
+    pub fn ensure_units_index(&self) -> Result<usize> {
+        let units_index = self.units_index()?;
+        let mut batch = rocksdb::WriteBatch::default();
+        for entry in self.entries(&kv::KvRange::time(None, None))? {
+            let (_, interval) = entry?;
+            let index_key = kv::units_index_key(&interval);
+            if self.db.get_cf(units_index, &index_key)?.is_none() {
+                batch.put_cf(units_index, index_key, serde_json::to_vec(&interval)?);
+            }
+        }
+
+        let indexed = batch.len();
+        self.db.write_opt(batch, &self.write_options())?;
+
+        tracing::info!("Indexed {} RocksDB intervals by units", indexed);
+        Ok(indexed)
+    }
+
+    // Rewrites keys from the old "{start}:{end}" string layout to the binary layout
+    pub fn migrate_legacy_keys(&self) -> Result<usize> {
+        let mut batch = rocksdb::WriteBatch::default();
+        let mut migrated = 0;
+        for item in self.db.iterator(rocksdb::IteratorMode::Start) {
+            let (key, value) = item?;
+            if let Some(new_key) = kv::migrate_legacy_key(&key) {
+                batch.put(new_key, value);
+                batch.delete(key);
+                migrated += 1;
+            }
+        }
+
+        self.db.write_opt(batch, &self.write_options())?;
+
+        tracing::info!("Migrated {} legacy RocksDB keys", migrated);
+        Ok(migrated)
+    }
+}
+
+impl RunepoolStore for RocksStore {
+    const DATABASE_TYPE: DatabaseType = DatabaseType::RocksDB;
+
+    async fn insert(&self, intervals: &[RunepoolUnitsInterval]) -> Result<usize> {
+        let keys: Vec<Vec<u8>> = intervals.iter().map(kv::key_for_interval).collect();
+
+        // Read before write is timed on its own so it can be told apart from the write cost
+        let read_metrics = OperationMetrics::new(
+            DatabaseType::RocksDB,
+            DatabaseOperation::Read,
+            keys.len(),
+            "runepool units (existing keys)".to_string(),
+        );
+        let existing = self.db.multi_get(&keys);
+        read_metrics.finish();
+
+        let metrics = OperationMetrics::new(
+            DatabaseType::RocksDB,
+            DatabaseOperation::Write,
+            intervals.len(),
+            "runepool units (write batch)".to_string(),
+        );
+
+        // Interval and its units index entry go in the same batch so they never drift apart
+        let units_index = self.units_index()?;
+        let mut batch = rocksdb::WriteBatch::default();
+        let mut batched_keys = HashSet::new();
+        for ((interval, key), existing) in intervals.iter().zip(&keys).zip(existing) {
+            if existing?.is_none() && batched_keys.insert(key) {
+                let value = serde_json::to_vec(interval)?;
+                batch.put_cf(units_index, kv::units_index_key(interval), &value);
+                batch.put(key, value);
+            }
+        }
+
+        // The whole batch lands atomically
+        let stored_count = batched_keys.len();
+        self.db.write_opt(batch, &self.write_options())?;
+
+        metrics.finish();
+        Ok(stored_count)
+    }
+
+    async fn query(&self, query: &HistoryQuery) -> Result<Vec<RunepoolUnitsInterval>> {
+        let metrics = OperationMetrics::new(
+            DatabaseType::RocksDB,
+            DatabaseOperation::Read,
+            query.limit as usize,
+            "runepool units".to_string(),
+        );
+
+        let start_time_metric = Instant::now();
+
+        let range = kv::KvRange::for_page(query);
+        let mut page = kv::Page::new(query, &range);
+        for entry in self.entries(&range)? {
+            let (_, interval) = entry?;
+            if !page.push(interval) {
+                break;
+            }
+        }
+        let results = page.into_results();
+
+        log_db_operation_metrics(
+            &format!("read_intervals_{}_records", results.len()),
+            start_time_metric,
+        );
+
+        metrics.finish();
+        Ok(results)
+    }
+
+    async fn resample(
+        &self,
+        query: &HistoryQuery,
+        resample: Resample,
+    ) -> Result<Vec<RunepoolUnitsInterval>> {
+        let mut metrics = OperationMetrics::new(
+            DatabaseType::RocksDB,
+            DatabaseOperation::Aggregate,
+            0,
+            format!("runepool units (by {})", resample.interval),
+        );
+
+        let range = kv::KvRange::for_resample(query);
+        let mut buckets = kv::Buckets::new(query, resample, &range);
+        for entry in self.entries(&range)? {
+            let (_, interval) = entry?;
+            if !buckets.push(interval) {
+                break;
+            }
+        }
+        let results = buckets.into_results();
+
+        metrics.set_record_count(results.len());
+        metrics.finish();
+        Ok(results)
+    }
 
-    for interval in intervals {
-        let key = format!(
-            "{}:{}",
-            interval.start_time.timestamp(),
-            interval.end_time.timestamp()
+    async fn deltas(&self, query: &HistoryQuery) -> Result<Vec<RunepoolUnitsDelta>> {
+        let mut metrics = OperationMetrics::new(
+            DatabaseType::RocksDB,
+            DatabaseOperation::Read,
+            0,
+            "runepool unit deltas".to_string(),
         );
 
-        // Check if record exists
-        if db.get(key.as_bytes())?.is_none() {
-            let value = serde_json::to_vec(&interval)?;
-            db.put(key.as_bytes(), value)?;
+        // One streaming pass over the keys in time order, plus a single step back for
+        // the interval before an ascending page
+        let mut deltas = kv::Deltas::new(query);
+        if let Some(seed_range) = deltas.seed_range() {
+            if let Some(entry) = self.entries(&seed_range)?.next() {
+                deltas.seed(entry?.1);
+            }
         }
+        for entry in self.entries(&deltas.range())? {
+            let (_, interval) = entry?;
+            if !deltas.push(interval) {
+                break;
+            }
+        }
+        let results = deltas.into_results();
+
+        metrics.set_record_count(results.len());
+        metrics.finish();
+        Ok(results)
     }
 
-    // Ensure data is written to disk
-    db.flush()?;
+    async fn meta(&self, query: &HistoryQuery) -> Result<Option<MetaStats>> {
+        let mut metrics = OperationMetrics::new(
+            DatabaseType::RocksDB,
+            DatabaseOperation::Read,
+            0,
+            "runepool units (meta)".to_string(),
+        );
+
+        // Forward and backward from the ends of the time range, both stop at the first match
+        let Some(first) = self.first_match(&kv::KvRange::for_meta(query, false), query)? else {
+            metrics.finish();
+            return Ok(None);
+        };
+        let last = self
+            .first_match(&kv::KvRange::for_meta(query, true), query)?
+            .unwrap_or_else(|| first.clone());
+
+        metrics.set_record_count(2);
+        metrics.finish();
+        Ok(Some(MetaStats::new(&first, &last)))
+    }
+
+    async fn stats(&self, query: &HistoryQuery) -> Result<Option<RunepoolUnitsStats>> {
+        let mut metrics = OperationMetrics::new(
+            DatabaseType::RocksDB,
+            DatabaseOperation::Stats,
+            0,
+            "runepool units".to_string(),
+        );
 
-    metrics.finish();
-    Ok(())
+        // Summarized in rust over a time ordered scan
+        let mut summary = kv::Summary::new(query);
+        for entry in self.entries(&kv::KvRange::time(query.start_time, query.end_time))? {
+            let (_, interval) = entry?;
+            summary.push(interval);
+        }
+        let stats = summary.finish();
+
+        metrics.set_record_count(stats.as_ref().map_or(0, |stats| stats.intervals as usize));
+        metrics.finish();
+        Ok(stats)
+    }
+
+    async fn gaps(&self, query: &HistoryQuery) -> Result<Option<RunepoolUnitsGaps>> {
+        let mut metrics = OperationMetrics::new(
+            DatabaseType::RocksDB,
+            DatabaseOperation::Read,
+            0,
+            "runepool unit gaps (key range)".to_string(),
+        );
+
+        // The keys carry the times and come in time order, values are never read
+        let mut scan = GapsScan::default();
+        let range = kv::KvRange::time(query.start_time, query.end_time);
+        for item in self.raw_entries(&range)? {
+            let (key, _) = item?;
+            if let Some((start, end)) = kv::interval_key_times(&key) {
+                if query.in_time_range(start, end) {
+                    scan.push(start, end);
+                }
+            }
+        }
+        let gaps = scan.finish();
+
+        metrics.set_record_count(gaps.as_ref().map_or(0, |gaps| gaps.intervals as usize));
+        metrics.finish();
+        Ok(gaps)
+    }
+
+    async fn count(&self, query: &HistoryQuery) -> Result<u64> {
+        let mut metrics = OperationMetrics::new(
+            DatabaseType::RocksDB,
+            DatabaseOperation::Count,
+            0,
+            "runepool units (key range)".to_string(),
+        );
+
+        // Keys are enough unless the query filters on count
+        let range = kv::KvRange::for_count(query);
+        let mut count = 0;
+        for item in self.raw_entries(&range)? {
+            let (key, value) = item?;
+            if range.entry_matches(query, &key, &value) {
+                count += 1;
+            }
+        }
+
+        metrics.set_record_count(count as usize);
+        metrics.finish();
+        Ok(count)
+    }
+
+    async fn delete_range(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<usize> {
+        let units_index = self.units_index()?;
+        let mut batch = rocksdb::WriteBatch::default();
+        let mut deleted = 0;
+        for entry in self.entries(&kv::KvRange::time(Some(start), Some(end)))? {
+            let (key, interval) = entry?;
+            if interval.start_time >= start && interval.end_time <= end {
+                batch.delete_cf(units_index, kv::units_index_key(&interval));
+                batch.delete(key);
+                deleted += 1;
+            }
+        }
+
+        self.db.write_opt(batch, &self.write_options())?;
+        Ok(deleted)
+    }
+
+    async fn health(&self) -> Result<()> {
+        self.db.property_value("rocksdb.stats")?;
+        Ok(())
+    }
 }
@@ -1,99 +1,94 @@
-use crate::config::connect::{LEVEL_DB, PG_POOL, ROCKS_DB};
+use crate::config::connect::{DB, LEVEL_DB, MONGO_CLIENT, PG_POOL, ROCKS_DB};
 use crate::core::models::runepool_units_history::RunepoolUnitsInterval;
 use anyhow::Result;
 use tokio;
+use tokio::task::JoinHandle;
 
-use super::leveldb::store_level_intervals;
-use super::mongodb::store_mongo_intervals;
-use super::postgres::store_postgres_intervals;
-use super::rocksdb::store_rocks_intervals;
-use super::surrealdb::store_surreal_intervals;
+use super::leveldb::LevelStore;
+use super::mongodb::MongoStore;
+use super::postgres::PostgresStore;
+use super::rocksdb::RocksStore;
+use super::surrealdb::SurrealStore;
+use super::RunepoolStore;
 
-// Main store function that coordinates all storage operations
-pub async fn store_intervals(intervals: Vec<RunepoolUnitsInterval>) -> Result<(), anyhow::Error> {
-    let intervals_clone1 = intervals.clone();
-    let intervals_clone2 = intervals.clone();
-    let intervals_clone3 = intervals.clone();
-    let intervals_clone4 = intervals.clone();
-    let intervals_clone5 = intervals;
+fn spawn_store<S: RunepoolStore>(
+    store: Option<S>,
+    intervals: Vec<RunepoolUnitsInterval>,
+) -> JoinHandle<Result<(), anyhow::Error>> {
+    let db_name = S::DATABASE_TYPE.name();
 
-    let pg_task = tokio::spawn(async move {
-        if let Some(pool) = PG_POOL.get() {
-            match store_postgres_intervals(pool, &intervals_clone1).await {
-                Ok(_) => {
-                    tracing::info!("Successfully stored intervals in PostgreSQL");
-                    Ok(())
-                }
-                Err(e) => {
-                    tracing::error!("Failed to store in PostgreSQL: {}", e);
-                    Err(anyhow::anyhow!("PostgreSQL storage failed: {}", e))
-                }
-            }
-        } else {
-            Ok(())
-        }
-    });
+    tokio::spawn(async move {
+        let Some(store) = store else {
+            tracing::info!("might be {} not initialized", db_name);
+            return Ok(());
+        };
 
-    let surreal_task = tokio::spawn(async move {
-        match store_surreal_intervals(intervals_clone2).await {
-            Ok(_) => {
-                tracing::info!("Successfully stored intervals in SurrealDB");
+        match store.insert(&intervals).await {
+            Ok(stored_count) => {
+                tracing::info!(
+                    "Successfully stored {} new intervals in {}",
+                    stored_count,
+                    db_name
+                );
                 Ok(())
             }
             Err(e) => {
-                tracing::error!("Failed to store in SurrealDB: {}", e);
-                Err(anyhow::anyhow!("SurrealDB storage failed: {}", e))
+                tracing::error!("Failed to store in {}: {}", db_name, e);
+                Err(anyhow::anyhow!("{} storage failed: {}", db_name, e))
             }
         }
-    });
+    })
+}
 
-    let mongo_task = tokio::spawn(async move {
-        match store_mongo_intervals(intervals_clone5).await {
-            Ok(_) => {
-                tracing::info!("Successfully stored intervals in MongoDB");
-                Ok(())
-            }
-            Err(e) => {
-                tracing::error!("Failed to store in MongoDB: {}", e);
-                Err(anyhow::anyhow!("MongoDB storage failed: {}", e))
-            }
+// One-off schema, index and key migrations, run once every database is connected
+pub async fn prepare_stores() {
+    if let Err(e) = SurrealStore::new(DB.clone()).migrate().await {
+        tracing::error!("Failed to migrate SurrealDB records: {}", e);
+    }
+
+    if let Some(client) = MONGO_CLIENT.get() {
+        if let Err(e) = MongoStore::new(client.clone()).ensure_indexes().await {
+            tracing::error!("Failed to create MongoDB indexes: {}", e);
         }
-    });
+    }
 
-    let rocks_task = tokio::spawn(async move {
-        if let Some(db) = ROCKS_DB.get() {
-            match store_rocks_intervals(db.clone(), intervals_clone3).await {
-                Ok(()) => {
-                    tracing::info!("Successfully stored intervals in RocksDB");
-                    Ok(())
-                }
-                Err(e) => {
-                    tracing::error!("Failed to store in RocksDB: {}", e);
-                    Err(anyhow::anyhow!("RocksDB storage failed: {}", e))
-                }
-            }
-        } else {
-            tracing::info!("might be rocks db not initialized");
-            Ok(()) // This silently succeeds when RocksDB is not initialized!
+    if let Some(db) = ROCKS_DB.get() {
+        let store = RocksStore::new(db.clone());
+        if let Err(e) = store.migrate_legacy_keys() {
+            tracing::error!("Failed to migrate RocksDB keys: {}", e);
+        }
+        if let Err(e) = store.ensure_units_index() {
+            tracing::error!("Failed to build RocksDB units index: {}", e);
         }
-    });
+    }
 
-    let level_task = tokio::spawn(async move {
-        if let Some(db) = LEVEL_DB.get() {
-            match store_level_intervals(db.clone(), intervals_clone4).await {
-                Ok(_) => {
-                    tracing::info!("Successfully stored intervals in LevelDB");
-                    Ok(())
-                }
-                Err(e) => {
-                    tracing::error!("Failed to store in LevelDB: {}", e);
-                    Err(anyhow::anyhow!("LevelDB storage failed: {}", e))
-                }
-            }
-        } else {
-            Ok(())
+    if let Some(db) = LEVEL_DB.get() {
+        let store = LevelStore::new(db.clone());
+        if let Err(e) = store.migrate_legacy_keys() {
+            tracing::error!("Failed to migrate LevelDB keys: {}", e);
+        }
+        if let Err(e) = store.ensure_units_index() {
+            tracing::error!("Failed to build LevelDB units index: {}", e);
         }
-    });
+    }
+}
+
+// Main store function that coordinates all storage operations
+pub async fn store_intervals(intervals: Vec<RunepoolUnitsInterval>) -> Result<(), anyhow::Error> {
+    let pg_task = spawn_store(
+        PG_POOL.get().cloned().map(PostgresStore::new),
+        intervals.clone(),
+    );
+    let surreal_task = spawn_store(Some(SurrealStore::new(DB.clone())), intervals.clone());
+    let mongo_task = spawn_store(
+        MONGO_CLIENT.get().cloned().map(MongoStore::new),
+        intervals.clone(),
+    );
+    let rocks_task = spawn_store(
+        ROCKS_DB.get().cloned().map(RocksStore::new),
+        intervals.clone(),
+    );
+    let level_task = spawn_store(LEVEL_DB.get().cloned().map(LevelStore::new), intervals);
 
     let (pg_result, surreal_result, rocks_result, level_result, mongo_result) =
         tokio::join!(pg_task, surreal_task, rocks_task, level_task, mongo_task);
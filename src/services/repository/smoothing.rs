@@ -0,0 +1,171 @@
+use crate::core::models::common::{HistoryCursor, SortField, SortOrder};
+use crate::core::models::runepool_units_history::{
+    RunepoolUnitsInterval, SmoothedRunepoolUnitsInterval,
+};
+use anyhow::{ensure, Result};
+
+use super::{HistoryQuery, Resample, RunepoolStore};
+
+// Windows of intervals before the page the EMA starts from
+const EMA_WARM_UP_SPANS: u32 = 10;
+
+// Moving averages of the units, each one N intervals (or buckets) wide
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Smoothing {
+    pub sma: Option<u32>,
+    pub ema: Option<u32>,
+}
+
+impl Smoothing {
+    pub fn is_requested(&self) -> bool {
+        self.sma.is_some() || self.ema.is_some()
+    }
+
+    // How many intervals before the page the averages need: N - 1 for the SMA. Every
+    // value the EMA has seen still weighs in, but past 10 windows back they count for
+    // (1 - 2 / (N + 1))^(10N) of it at most, less than e^-20 (2e-9)
+    fn warm_up(&self) -> u32 {
+        let sma = self.sma.map_or(0, |sma| sma.saturating_sub(1));
+        let ema = self
+            .ema
+            .map_or(0, |ema| ema.saturating_mul(EMA_WARM_UP_SPANS));
+        sma.max(ema)
+    }
+}
+
+// The part of the series that comes right before the page, oldest first
+pub(super) async fn before_page<S: RunepoolStore>(
+    store: &S,
+    query: &HistoryQuery,
+    resample: Option<Resample>,
+    page: &[RunepoolUnitsInterval],
+    limit: u32,
+) -> Result<Vec<RunepoolUnitsInterval>> {
+    let oldest = match query.sort_order {
+        SortOrder::Asc => page.first(),
+        SortOrder::Desc => page.last(),
+    };
+    let Some(oldest) = oldest.filter(|_| limit > 0) else {
+        return Ok(Vec::new());
+    };
+
+    let before = |offset, limit, sort_order, after| HistoryQuery {
+        limit,
+        offset,
+        sort_order,
+        after,
+        ..query.clone()
+    };
+    let (mut earlier, sort_order) = match (resample, query.sort_order) {
+        // Intervals going back in time from the oldest one on the page
+        (None, _) => {
+            let after = HistoryCursor::after(oldest, SortField::StartTime, SortOrder::Desc);
+            let earlier = before(0, limit, SortOrder::Desc, Some(after));
+            (store.query(&earlier).await?, SortOrder::Desc)
+        }
+        // Buckets only page by offset, the ones before the page sit right before its offset
+        (Some(resample), SortOrder::Asc) => {
+            let offset = query.offset.saturating_sub(limit);
+            let earlier = before(offset, query.offset - offset, SortOrder::Asc, None);
+            if earlier.limit == 0 {
+                return Ok(Vec::new());
+            }
+            (store.resample(&earlier, resample).await?, SortOrder::Asc)
+        }
+        (Some(resample), SortOrder::Desc) => {
+            let offset = query.offset.saturating_add(page.len() as u32);
+            let earlier = before(offset, limit, SortOrder::Desc, None);
+            (store.resample(&earlier, resample).await?, SortOrder::Desc)
+        }
+    };
+
+    if sort_order == SortOrder::Desc {
+        earlier.reverse();
+    }
+    Ok(earlier)
+}
+
+// Averages over a series in time order. The SMA averages whatever is there while the
+// series is shorter than its window, the EMA starts from the first value
+fn averages(units: &[u64], smoothing: Smoothing) -> (Vec<Option<f64>>, Vec<Option<f64>>) {
+    let sma = match smoothing.sma {
+        Some(window) => {
+            let window = window as usize;
+            let mut sum: u128 = 0;
+            units
+                .iter()
+                .enumerate()
+                .map(|(i, &value)| {
+                    sum += value as u128;
+                    if i >= window {
+                        sum -= units[i - window] as u128;
+                    }
+                    Some(sum as f64 / (i + 1).min(window) as f64)
+                })
+                .collect()
+        }
+        None => vec![None; units.len()],
+    };
+
+    let ema = match smoothing.ema {
+        Some(window) => {
+            let alpha = 2.0 / (window as f64 + 1.0);
+            let mut previous: Option<f64> = None;
+            units
+                .iter()
+                .map(|&value| {
+                    let value = value as f64;
+                    let ema =
+                        previous.map_or(value, |previous| alpha * value + (1.0 - alpha) * previous);
+                    previous = Some(ema);
+                    Some(ema)
+                })
+                .collect()
+        }
+        None => vec![None; units.len()],
+    };
+
+    (sma, ema)
+}
+
+// Adds the averages to a page of history sorted by time. They run over the whole series
+// the query matches, not just the page, so the intervals before the page are fetched first
+pub async fn smooth<S: RunepoolStore>(
+    store: &S,
+    query: &HistoryQuery,
+    resample: Option<Resample>,
+    page: Vec<RunepoolUnitsInterval>,
+    smoothing: Smoothing,
+) -> Result<Vec<SmoothedRunepoolUnitsInterval>> {
+    ensure!(
+        query.sort_field == SortField::StartTime,
+        "moving averages need the history sorted by time"
+    );
+
+    let mut series = before_page(store, query, resample, &page, smoothing.warm_up()).await?;
+    let warm_up_len = series.len();
+    match query.sort_order {
+        SortOrder::Asc => series.extend(page),
+        SortOrder::Desc => series.extend(page.into_iter().rev()),
+    }
+
+    let units: Vec<u64> = series.iter().map(|interval| interval.units).collect();
+    let (sma, ema) = averages(&units, smoothing);
+
+    let mut smoothed: Vec<SmoothedRunepoolUnitsInterval> = series
+        .into_iter()
+        .zip(sma.into_iter().zip(ema))
+        .skip(warm_up_len)
+        .map(
+            |(interval, (units_sma, units_ema))| SmoothedRunepoolUnitsInterval {
+                interval,
+                units_sma,
+                units_ema,
+            },
+        )
+        .collect();
+    if query.sort_order == SortOrder::Desc {
+        smoothed.reverse();
+    }
+    Ok(smoothed)
+}
@@ -1,38 +1,625 @@
-use crate::config::connect::DB;
-use crate::core::models::runepool_units_history::RunepoolUnitsInterval;
-use crate::utils::metrics::{DatabaseOperation, DatabaseType, OperationMetrics};
-
-// Store in SurrealDB
-pub async fn store_surreal_intervals(
-    intervals: Vec<RunepoolUnitsInterval>,
-) -> surrealdb::Result<usize> {
-    let metrics = OperationMetrics::new(
-        DatabaseType::SurrealDB,
-        DatabaseOperation::Write,
-        intervals.len(),
-        "runepool units".to_string(),
-    );
-
-    let mut stored_count = 0;
-    for interval in intervals {
-        let existing: Option<RunepoolUnitsInterval> = DB
+use super::{stored_int, GapsScan, HistoryQuery, Resample, RunepoolStore};
+use crate::core::models::common::{CountMode, Interval, SortField, SortOrder, ValueRange};
+use crate::core::models::runepool_units_history::{
+    FieldStats, MetaStats, RunepoolUnitsDelta, RunepoolUnitsGaps, RunepoolUnitsInterval,
+    RunepoolUnitsStats,
+};
+use crate::utils::metrics::{
+    log_db_operation_metrics, DatabaseOperation, DatabaseType, OperationMetrics,
+};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
+use surrealdb::sql::{Array, Datetime, Id, Thing, Value};
+use surrealdb::{engine::remote::ws::Client, method::Query, Surreal};
+
+#[derive(Clone)]
+pub struct SurrealStore {
+    db: Surreal<Client>,
+}
+
+// Record as stored in SurrealDB, the api model serializes everything as strings
+// which surreal can't order or compare properly so it gets native types here
+#[derive(Debug, Serialize, Deserialize)]
+struct SurrealRunepoolUnitsInterval {
+    // runepool_unit_intervals:[start_time, end_time], only sent when writing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<Thing>,
+    start_time: Datetime,
+    end_time: Datetime,
+    count: i64,
+    units: i64,
+}
+
+impl From<&RunepoolUnitsInterval> for SurrealRunepoolUnitsInterval {
+    fn from(interval: &RunepoolUnitsInterval) -> Self {
+        let start_time = Datetime::from(interval.start_time);
+        let end_time = Datetime::from(interval.end_time);
+        Self {
+            id: Some(record_id(&start_time, &end_time)),
+            start_time,
+            end_time,
+            count: interval.count as i64,
+            units: interval.units as i64,
+        }
+    }
+}
+
+// Just the times, all the gaps report reads
+#[derive(Debug, Deserialize)]
+struct SurrealTimes {
+    start_time: Datetime,
+    end_time: Datetime,
+}
+
+// Deterministic record id, the same interval always maps to the same record
+fn record_id(start_time: &Datetime, end_time: &Datetime) -> Thing {
+    let key = Array::from(vec![
+        Value::from(start_time.clone()),
+        Value::from(end_time.clone()),
+    ]);
+    Thing::from(("runepool_unit_intervals", Id::Array(key)))
+}
+
+impl From<SurrealRunepoolUnitsInterval> for RunepoolUnitsInterval {
+    fn from(record: SurrealRunepoolUnitsInterval) -> Self {
+        Self {
+            start_time: record.start_time.into(),
+            end_time: record.end_time.into(),
+            count: record.count as u64,
+            units: record.units as u64,
+        }
+    }
+}
+
+impl SurrealStore {
+    pub fn new(db: Surreal<Client>) -> Self {
+        Self { db }
+    }
+
+    // One-shot rewrite of records stored with the old string encoded fields
+    // (startTime/endTime unix strings, count/units numeric strings) and random
+    // ids, then the field types and the unique index get enforced
+    pub async fn migrate(&self) -> Result<usize> {
+        let migrated: Vec<SurrealRunepoolUnitsInterval> = self
+            .db
+            .query(
+                "UPDATE runepool_unit_intervals SET
+                    start_time = time::from::unix(<int> startTime),
+                    end_time = time::from::unix(<int> endTime),
+                    count = <int> count,
+                    units = <int> units,
+                    startTime = NONE,
+                    endTime = NONE
+                WHERE type::is::string(startTime)",
+            )
+            .await?
+            .take(0)?;
+
+        // Random ids are moved to the deterministic [start, end] key, UPSERT folds the
+        // duplicates the old read-then-create path let through into one record
+        self.db
             .query(
-                "SELECT * FROM runepool_unit_intervals WHERE startTime = $start AND endTime = $end",
+                "FOR $record IN (SELECT * FROM runepool_unit_intervals WHERE !type::is::array(record::id(id))) {
+                    DELETE $record.id;
+                    UPSERT type::thing('runepool_unit_intervals', [$record.start_time, $record.end_time]) CONTENT {
+                        start_time: $record.start_time,
+                        end_time: $record.end_time,
+                        count: $record.count,
+                        units: $record.units
+                    };
+                }",
             )
-            .bind(("start", interval.start_time))
-            .bind(("end", interval.end_time))
+            .await?
+            .check()?;
+
+        self.db
+            .query(
+                "DEFINE FIELD IF NOT EXISTS start_time ON runepool_unit_intervals TYPE datetime;
+                DEFINE FIELD IF NOT EXISTS end_time ON runepool_unit_intervals TYPE datetime;
+                DEFINE FIELD IF NOT EXISTS count ON runepool_unit_intervals TYPE int;
+                DEFINE FIELD IF NOT EXISTS units ON runepool_unit_intervals TYPE int;
+                DEFINE INDEX IF NOT EXISTS start_time_end_time_unique ON runepool_unit_intervals FIELDS start_time, end_time UNIQUE;",
+            )
+            .await?
+            .check()?;
+
+        tracing::info!(
+            "Migrated {} string encoded intervals in SurrealDB",
+            migrated.len()
+        );
+        Ok(migrated.len())
+    }
+}
+
+fn where_clause(query: &HistoryQuery, after_cursor: bool) -> String {
+    let mut conditions = Vec::new();
+
+    if query.start_time.is_some() {
+        conditions.push("start_time >= $start");
+    }
+
+    if query.end_time.is_some() {
+        conditions.push("end_time <= $end");
+    }
+
+    for (bound, condition) in [
+        (query.units.min, "units >= $units_min"),
+        (query.units.max, "units <= $units_max"),
+        (query.count.min, "count >= $count_min"),
+        (query.count.max, "count <= $count_max"),
+    ] {
+        if bound.is_some() {
+            conditions.push(condition);
+        }
+    }
+
+    if let Some(cursor) = query.after.filter(|_| after_cursor) {
+        conditions.push(after_clause(cursor.sort_field, cursor.sort_order));
+    }
+
+    if conditions.is_empty() {
+        String::new()
+    } else {
+        format!(" WHERE {}", conditions.join(" AND "))
+    }
+}
+
+// Keyset condition for the intervals after the cursor, ties go by start time ascending
+fn after_clause(sort_field: SortField, sort_order: SortOrder) -> &'static str {
+    match (sort_field, sort_order) {
+        (SortField::StartTime, SortOrder::Asc) => "start_time > $after_start",
+        (SortField::StartTime, SortOrder::Desc) => "start_time < $after_start",
+        (SortField::Units, SortOrder::Asc) => {
+            "(units > $after_value OR (units = $after_value AND start_time > $after_start))"
+        }
+        (SortField::Units, SortOrder::Desc) => {
+            "(units < $after_value OR (units = $after_value AND start_time > $after_start))"
+        }
+        (SortField::Count, SortOrder::Asc) => {
+            "(count > $after_value OR (count = $after_value AND start_time > $after_start))"
+        }
+        (SortField::Count, SortOrder::Desc) => {
+            "(count < $after_value OR (count = $after_value AND start_time > $after_start))"
+        }
+    }
+}
+
+fn bind_filters<'r>(request: Query<'r, Client>, query: &HistoryQuery) -> Query<'r, Client> {
+    request
+        .bind(("start", query.start_time.map(Datetime::from)))
+        .bind(("end", query.end_time.map(Datetime::from)))
+        .bind(("units_min", query.units.min.map(stored_int)))
+        .bind(("units_max", query.units.max.map(stored_int)))
+        .bind(("count_min", query.count.min.map(stored_int)))
+        .bind(("count_max", query.count.max.map(stored_int)))
+        .bind((
+            "after_start",
+            query.after.map(|cursor| Datetime::from(cursor.start_time)),
+        ))
+        .bind((
+            "after_value",
+            query.after.map(|cursor| stored_int(cursor.value)),
+        ))
+}
+
+// ORDER BY can't take a variable, so only these fixed clauses ever reach the query
+fn order_clause(query: &HistoryQuery) -> &'static str {
+    match (query.sort_field, query.sort_order) {
+        (SortField::StartTime, SortOrder::Asc) => " ORDER BY start_time ASC, end_time ASC",
+        (SortField::StartTime, SortOrder::Desc) => " ORDER BY start_time DESC, end_time DESC",
+        (SortField::Units, SortOrder::Asc) => " ORDER BY units ASC, start_time ASC, end_time ASC",
+        (SortField::Units, SortOrder::Desc) => " ORDER BY units DESC, start_time ASC, end_time ASC",
+        (SortField::Count, SortOrder::Asc) => " ORDER BY count ASC, start_time ASC, end_time ASC",
+        (SortField::Count, SortOrder::Desc) => " ORDER BY count DESC, start_time ASC, end_time ASC",
+    }
+}
+
+// Start of the bucket an interval falls in. time::floor counts from the unix epoch,
+// a thursday, so weeks are shifted to start on monday. Quarters have no builtin
+fn bucket_expression(interval: Interval) -> &'static str {
+    match interval {
+        Interval::FiveMin => "time::floor(start_time, 5m)",
+        Interval::Hour => "time::floor(start_time, 1h)",
+        Interval::Day => "time::floor(start_time, 1d)",
+        Interval::Week => "time::floor(start_time - 4d, 1w) + 4d",
+        Interval::Month => "time::group(start_time, 'month')",
+        Interval::Quarter => {
+            "<datetime> (time::format(start_time, '%Y-')
+                + ['01', '01', '01', '04', '04', '04', '07', '07', '07', '10', '10', '10'][time::month(start_time) - 1]
+                + '-01T00:00:00Z')"
+        }
+        Interval::Year => "time::group(start_time, 'year')",
+    }
+}
+
+#[derive(Deserialize)]
+struct SurrealBucket {
+    start_time: Datetime,
+    count: i64,
+    units: i64,
+}
+
+// Aggregates of the stats query, no row comes back when nothing matches
+#[derive(Deserialize)]
+struct SurrealStats {
+    intervals: u64,
+    start_time: Datetime,
+    end_time: Datetime,
+    units_min: i64,
+    units_max: i64,
+    units_avg: f64,
+    units_stddev: f64,
+    units_first: i64,
+    units_last: i64,
+    count_min: i64,
+    count_max: i64,
+    count_avg: f64,
+    count_stddev: f64,
+    count_first: i64,
+    count_last: i64,
+}
+
+impl From<SurrealStats> for RunepoolUnitsStats {
+    fn from(row: SurrealStats) -> Self {
+        Self {
+            start_time: row.start_time.into(),
+            end_time: row.end_time.into(),
+            intervals: row.intervals,
+            units: FieldStats::new(
+                row.units_min as u64,
+                row.units_max as u64,
+                row.units_avg,
+                row.units_stddev,
+                row.units_first as u64,
+                row.units_last as u64,
+            ),
+            count: FieldStats::new(
+                row.count_min as u64,
+                row.count_max as u64,
+                row.count_avg,
+                row.count_stddev,
+                row.count_first as u64,
+                row.count_last as u64,
+            ),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct CountRow {
+    count: u64,
+}
+
+impl RunepoolStore for SurrealStore {
+    const DATABASE_TYPE: DatabaseType = DatabaseType::SurrealDB;
+
+    async fn insert(&self, intervals: &[RunepoolUnitsInterval]) -> Result<usize> {
+        let metrics = OperationMetrics::new(
+            DatabaseType::SurrealDB,
+            DatabaseOperation::Write,
+            intervals.len(),
+            "runepool units".to_string(),
+        );
+
+        let records: Vec<SurrealRunepoolUnitsInterval> = intervals.iter().map(Into::into).collect();
+
+        // Ids already stored are ignored, only the records that were actually new come back
+        let inserted: Vec<SurrealRunepoolUnitsInterval> = self
+            .db
+            .query("INSERT IGNORE INTO runepool_unit_intervals $records")
+            .bind(("records", records))
+            .await?
+            .take(0)?;
+        let stored_count = inserted.len();
+
+        metrics.finish();
+        Ok(stored_count)
+    }
+
+    async fn query(&self, query: &HistoryQuery) -> Result<Vec<RunepoolUnitsInterval>> {
+        let metrics = OperationMetrics::new(
+            DatabaseType::SurrealDB,
+            DatabaseOperation::Read,
+            query.limit as usize,
+            "runepool units".to_string(),
+        );
+
+        let mut surql =
+            String::from("SELECT start_time, end_time, count, units FROM runepool_unit_intervals");
+        surql.push_str(&where_clause(query, true));
+        surql.push_str(order_clause(query));
+        surql.push_str(" LIMIT $limit START $offset");
+
+        let start_time = Instant::now();
+        let records: Vec<SurrealRunepoolUnitsInterval> = bind_filters(self.db.query(surql), query)
+            .bind(("limit", query.limit))
+            .bind(("offset", query.offset))
+            .await?
+            .take(0)?;
+        let result: Vec<RunepoolUnitsInterval> = records.into_iter().map(Into::into).collect();
+        log_db_operation_metrics(
+            &format!("read_intervals_{}_records", result.len()),
+            start_time,
+        );
+
+        metrics.finish();
+        Ok(result)
+    }
+
+    async fn resample(
+        &self,
+        query: &HistoryQuery,
+        resample: Resample,
+    ) -> Result<Vec<RunepoolUnitsInterval>> {
+        let mut metrics = OperationMetrics::new(
+            DatabaseType::SurrealDB,
+            DatabaseOperation::Aggregate,
+            0,
+            format!("runepool units (by {})", resample.interval),
+        );
+
+        let count = match resample.count_mode {
+            CountMode::Last => "array::last(count)",
+            CountMode::Sum => "math::sum(count)",
+        };
+        // Rows go into the grouping in time order so array::last is the latest interval
+        // of the bucket. ORDER BY and LIMIT are only reliable outside of the GROUP BY
+        let surql = format!(
+            "SELECT * FROM (
+                SELECT bucket AS start_time, array::last(units) AS units, {} AS count
+                FROM (
+                    SELECT {} AS bucket, start_time, units, count
+                    FROM runepool_unit_intervals{} ORDER BY start_time ASC
+                )
+                GROUP BY start_time
+            ){} LIMIT $limit START $offset",
+            count,
+            bucket_expression(resample.interval),
+            where_clause(query, false),
+            order_clause(query)
+        );
+
+        let buckets: Vec<SurrealBucket> = bind_filters(self.db.query(surql), query)
+            .bind(("limit", query.limit))
+            .bind(("offset", query.offset))
+            .await?
+            .take(0)?;
+        let results: Vec<RunepoolUnitsInterval> = buckets
+            .into_iter()
+            .map(|bucket| {
+                let start_time = DateTime::<Utc>::from(bucket.start_time);
+                RunepoolUnitsInterval {
+                    start_time,
+                    end_time: resample.interval.bucket_end(start_time),
+                    count: bucket.count as u64,
+                    units: bucket.units as u64,
+                }
+            })
+            .collect();
+
+        metrics.set_record_count(results.len());
+        metrics.finish();
+        Ok(results)
+    }
+
+    async fn deltas(&self, query: &HistoryQuery) -> Result<Vec<RunepoolUnitsDelta>> {
+        let mut metrics = OperationMetrics::new(
+            DatabaseType::SurrealDB,
+            DatabaseOperation::Read,
+            0,
+            "runepool unit deltas".to_string(),
+        );
+
+        // SurrealQL has no window functions: the page comes back in time order, every
+        // interval is compared with its neighbour and the oldest one with the interval
+        // looked up right before it
+        let query = HistoryQuery {
+            units: ValueRange::default(),
+            count: ValueRange::default(),
+            sort_field: SortField::StartTime,
+            ..query.clone()
+        };
+        let order = match query.sort_order {
+            SortOrder::Asc => " ORDER BY start_time ASC, end_time ASC",
+            SortOrder::Desc => " ORDER BY start_time DESC, end_time DESC",
+        };
+        let surql = format!(
+            "SELECT start_time, end_time, count, units FROM runepool_unit_intervals{}{} LIMIT $limit START $offset",
+            where_clause(&query, true),
+            order
+        );
+        let records: Vec<SurrealRunepoolUnitsInterval> = bind_filters(self.db.query(surql), &query)
+            .bind(("limit", query.limit))
+            .bind(("offset", query.offset))
             .await?
             .take(0)?;
 
-        if existing.is_none() {
-            let _: Option<RunepoolUnitsInterval> = DB
-                .create("runepool_unit_intervals")
-                .content(interval)
-                .await?;
-            stored_count += 1;
+        let mut intervals: Vec<RunepoolUnitsInterval> =
+            records.into_iter().map(Into::into).collect();
+        if query.sort_order == SortOrder::Desc {
+            intervals.reverse();
+        }
+
+        let mut previous: Option<RunepoolUnitsInterval> = match intervals.first() {
+            Some(oldest) => {
+                let record: Option<SurrealRunepoolUnitsInterval> = self
+                    .db
+                    .query(
+                        "SELECT start_time, end_time, count, units FROM runepool_unit_intervals
+                        WHERE start_time < $start OR (start_time = $start AND end_time < $end)
+                        ORDER BY start_time DESC, end_time DESC LIMIT 1",
+                    )
+                    .bind(("start", Datetime::from(oldest.start_time)))
+                    .bind(("end", Datetime::from(oldest.end_time)))
+                    .await?
+                    .take(0)?;
+                record.map(Into::into)
+            }
+            None => None,
+        };
+
+        let mut results: Vec<RunepoolUnitsDelta> = intervals
+            .into_iter()
+            .map(|interval| {
+                let before = previous.replace(interval.clone());
+                RunepoolUnitsDelta::new(
+                    interval,
+                    before.as_ref().map(|before| before.units),
+                    before.as_ref().map(|before| before.count),
+                )
+            })
+            .collect();
+        if query.sort_order == SortOrder::Desc {
+            results.reverse();
+        }
+
+        metrics.set_record_count(results.len());
+        metrics.finish();
+        Ok(results)
+    }
+
+    async fn meta(&self, query: &HistoryQuery) -> Result<Option<MetaStats>> {
+        let metrics = OperationMetrics::new(
+            DatabaseType::SurrealDB,
+            DatabaseOperation::Read,
+            2,
+            "runepool units (meta)".to_string(),
+        );
+
+        // Both ends in one round trip, one statement each
+        let where_clause = where_clause(query, false);
+        let surql = format!(
+            "SELECT start_time, end_time, count, units FROM runepool_unit_intervals{where_clause}
+                ORDER BY start_time ASC, end_time ASC LIMIT 1;
+             SELECT start_time, end_time, count, units FROM runepool_unit_intervals{where_clause}
+                ORDER BY start_time DESC, end_time DESC LIMIT 1;"
+        );
+
+        let mut response = bind_filters(self.db.query(surql), query).await?;
+        let first: Option<SurrealRunepoolUnitsInterval> = response.take(0)?;
+        let last: Option<SurrealRunepoolUnitsInterval> = response.take(1)?;
+        let meta = match (first, last) {
+            (Some(first), Some(last)) => Some(MetaStats::new(&first.into(), &last.into())),
+            _ => None,
+        };
+
+        metrics.finish();
+        Ok(meta)
+    }
+
+    async fn stats(&self, query: &HistoryQuery) -> Result<Option<RunepoolUnitsStats>> {
+        let mut metrics = OperationMetrics::new(
+            DatabaseType::SurrealDB,
+            DatabaseOperation::Stats,
+            0,
+            "runepool units".to_string(),
+        );
+
+        // GROUP ALL keeps the order of the subquery, so array::first/last are the first
+        // and last intervals in time. math::stddev is the sample standard deviation
+        let surql = format!(
+            "SELECT count() AS intervals,
+                    array::first(start_time) AS start_time,
+                    array::last(end_time) AS end_time,
+                    math::min(units) AS units_min,
+                    math::max(units) AS units_max,
+                    math::mean(units) AS units_avg,
+                    math::stddev(units) AS units_stddev,
+                    array::first(units) AS units_first,
+                    array::last(units) AS units_last,
+                    math::min(count) AS count_min,
+                    math::max(count) AS count_max,
+                    math::mean(count) AS count_avg,
+                    math::stddev(count) AS count_stddev,
+                    array::first(count) AS count_first,
+                    array::last(count) AS count_last
+            FROM (
+                SELECT start_time, end_time, units, count
+                FROM runepool_unit_intervals{} ORDER BY start_time ASC, end_time ASC
+            )
+            GROUP ALL",
+            where_clause(query, false)
+        );
+
+        let row: Option<SurrealStats> = bind_filters(self.db.query(surql), query).await?.take(0)?;
+        let stats = row.map(RunepoolUnitsStats::from);
+
+        metrics.set_record_count(stats.as_ref().map_or(0, |stats| stats.intervals as usize));
+        metrics.finish();
+        Ok(stats)
+    }
+
+    async fn gaps(&self, query: &HistoryQuery) -> Result<Option<RunepoolUnitsGaps>> {
+        let mut metrics = OperationMetrics::new(
+            DatabaseType::SurrealDB,
+            DatabaseOperation::Read,
+            0,
+            "runepool unit gaps".to_string(),
+        );
+
+        // SurrealQL has no window functions: the times come back in order and each
+        // interval is compared with the one before it here
+        let query = HistoryQuery {
+            units: ValueRange::default(),
+            count: ValueRange::default(),
+            ..query.clone()
+        };
+        let surql = format!(
+            "SELECT start_time, end_time FROM runepool_unit_intervals{} ORDER BY start_time ASC, end_time ASC",
+            where_clause(&query, false)
+        );
+        let records: Vec<SurrealTimes> =
+            bind_filters(self.db.query(surql), &query).await?.take(0)?;
+
+        let mut scan = GapsScan::default();
+        for record in records {
+            scan.push(record.start_time.into(), record.end_time.into());
         }
+        let gaps = scan.finish();
+
+        metrics.set_record_count(gaps.as_ref().map_or(0, |gaps| gaps.intervals as usize));
+        metrics.finish();
+        Ok(gaps)
+    }
+
+    async fn count(&self, query: &HistoryQuery) -> Result<u64> {
+        let mut metrics = OperationMetrics::new(
+            DatabaseType::SurrealDB,
+            DatabaseOperation::Count,
+            0,
+            "runepool units".to_string(),
+        );
+
+        let surql = format!(
+            "SELECT count() FROM runepool_unit_intervals{} GROUP ALL",
+            where_clause(query, false)
+        );
+
+        let row: Option<CountRow> = bind_filters(self.db.query(surql), query).await?.take(0)?;
+        let count = row.map(|row| row.count).unwrap_or(0);
+
+        metrics.set_record_count(count as usize);
+        metrics.finish();
+        Ok(count)
+    }
+
+    async fn delete_range(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<usize> {
+        let deleted: Vec<SurrealRunepoolUnitsInterval> = self
+            .db
+            .query(
+                "DELETE runepool_unit_intervals WHERE start_time >= $start AND end_time <= $end RETURN BEFORE",
+            )
+            .bind(("start", Datetime::from(start)))
+            .bind(("end", Datetime::from(end)))
+            .await?
+            .take(0)?;
+        Ok(deleted.len())
     }
 
-    metrics.finish();
-    Ok(stored_count)
+    async fn health(&self) -> Result<()> {
+        self.db.health().await?;
+        Ok(())
+    }
 }
@@ -4,11 +4,16 @@ use std::io::Write;
 use std::time::Instant;
 use tracing::info;
 
+#[derive(Debug, Clone, Copy)]
 pub enum DatabaseOperation {
     Read,
     Write,
+    Count,
+    Aggregate,
+    Stats,
 }
 
+#[derive(Debug, Clone, Copy)]
 pub enum DatabaseType {
     MongoDB,
     Postgres,
@@ -17,6 +22,18 @@ pub enum DatabaseType {
     RocksDB,
 }
 
+impl DatabaseType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DatabaseType::MongoDB => "MongoDB",
+            DatabaseType::Postgres => "Postgres",
+            DatabaseType::SurrealDB => "SurrealDB",
+            DatabaseType::LevelDB => "LevelDB",
+            DatabaseType::RocksDB => "RocksDB",
+        }
+    }
+}
+
 pub struct OperationMetrics {
     db_type: DatabaseType,
     operation: DatabaseOperation,
@@ -41,20 +58,22 @@ impl OperationMetrics {
         }
     }
 
+    // For operations that only know how many records they touched once they are done
+    pub fn set_record_count(&mut self, record_count: usize) {
+        self.record_count = record_count;
+    }
+
     pub fn finish(self) {
         let duration = self.start_time.elapsed();
         let operation_type = match self.operation {
             DatabaseOperation::Read => "read",
             DatabaseOperation::Write => "insert",
+            DatabaseOperation::Count => "count",
+            DatabaseOperation::Aggregate => "aggregate",
+            DatabaseOperation::Stats => "compute stats of",
         };
 
-        let db_name = match self.db_type {
-            DatabaseType::MongoDB => "MongoDB",
-            DatabaseType::Postgres => "Postgres",
-            DatabaseType::SurrealDB => "SurrealDB",
-            DatabaseType::LevelDB => "LevelDB",
-            DatabaseType::RocksDB => "RocksDB",
-        };
+        let db_name = self.db_type.name();
 
         let message = format!(
             "Time taken for {} to {} {} data ({} records) : {}m {}s {}ms",
@@ -76,19 +95,21 @@ impl OperationMetrics {
 }
 
 fn write_to_metrics_file(message: &str) -> std::io::Result<()> {
-    let file_path = "performance_metrics.txt";
+    // METRICS_FILE lets test runs write somewhere else than the tracked results file
+    let file_path =
+        std::env::var("METRICS_FILE").unwrap_or_else(|_| "performance_metrics.txt".to_string());
     let mut file = OpenOptions::new()
         .create(true)
         .append(true)
-        .open(file_path)?;
+        .open(&file_path)?;
 
     // Add timestamp to the message
     let timestamp = Utc::now().format("%Y-%m-%d %H:%M:%S");
 
     // Check if this is a write or read operation
     if message.contains("insert") {
-        if !std::path::Path::new(file_path).exists()
-            || std::fs::read_to_string(file_path)?.is_empty()
+        if !std::path::Path::new(&file_path).exists()
+            || std::fs::read_to_string(&file_path)?.is_empty()
         {
             writeln!(file, "\nWRITE OPERATIONS: \n")?;
         }
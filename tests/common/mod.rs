@@ -0,0 +1,266 @@
+// Helpers shared by the integration tests. Backends other than the in-memory LevelDB
+// are #[ignore]d, `cargo test -- --ignored` runs them against the throwaway instances
+// their *_TEST_DATABASE_URL points at, the tests write and delete intervals in 2001 there
+#![allow(dead_code)]
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use db_tester::config::connect::open_rocksdb;
+use db_tester::core::models::common::{SortField, SortOrder, ValueRange};
+use db_tester::core::models::runepool_units_history::{
+    RunepoolUnitsHistoryQueryParams, RunepoolUnitsInterval,
+};
+use db_tester::services::repository::{
+    leveldb::LevelStore, mongodb::MongoStore, postgres::PostgresStore, rocksdb::RocksStore,
+    surrealdb::SurrealStore, HistoryQuery, RunepoolStore,
+};
+use std::cmp::Ordering;
+use std::sync::{Arc, Mutex};
+use surrealdb::engine::remote::ws::Ws;
+use surrealdb::opt::auth::Root;
+use surrealdb::Surreal;
+
+pub const SAMPLE_SIZE: i64 = 45;
+
+pub fn init() {
+    std::env::set_var(
+        "METRICS_FILE",
+        std::env::temp_dir().join("db_tester_test_metrics.txt"),
+    );
+}
+
+pub fn test_url(name: &str) -> String {
+    std::env::var(name).unwrap_or_else(|_| panic!("{} must be set to run this test", name))
+}
+
+// One test per backend running the check against that backend's store, as
+// backends::leveldb, backends::postgres...
+#[macro_export]
+macro_rules! backend_tests {
+    ($check:ident) => {
+        mod backends {
+            use $crate::common::*;
+
+            #[tokio::test]
+            async fn leveldb() {
+                init();
+                super::$check(&level_store()).await;
+            }
+
+            #[tokio::test]
+            #[ignore = "needs ROCKSDB_TEST_DATABASE_URL"]
+            async fn rocksdb() {
+                init();
+                super::$check(&rocks_store()).await;
+            }
+
+            #[tokio::test]
+            #[ignore = "needs POSTGRES_TEST_DATABASE_URL"]
+            async fn postgres() {
+                init();
+                super::$check(&postgres_store().await).await;
+            }
+
+            #[tokio::test]
+            #[ignore = "needs MONGODB_TEST_DATABASE_URL"]
+            async fn mongodb() {
+                init();
+                super::$check(&mongo_store().await).await;
+            }
+
+            #[tokio::test]
+            #[ignore = "needs SURREAL_TEST_DATABASE_URL"]
+            async fn surrealdb() {
+                init();
+                super::$check(&surreal_store().await).await;
+            }
+        }
+    };
+}
+
+// Hourly intervals with repeated units and counts so sorting has ties to break
+pub fn sample_intervals() -> Vec<RunepoolUnitsInterval> {
+    let start = Utc.with_ymd_and_hms(2001, 1, 1, 0, 0, 0).unwrap();
+    (0..SAMPLE_SIZE)
+        .map(|i| RunepoolUnitsInterval {
+            start_time: start + Duration::hours(i),
+            end_time: start + Duration::hours(i + 1),
+            count: (i % 4) as u64,
+            units: ((i * 37) % 7) as u64 * 100,
+        })
+        .collect()
+}
+
+// Time range covering every sample interval
+pub fn sample_window() -> (DateTime<Utc>, DateTime<Utc>) {
+    let intervals = sample_intervals();
+    (
+        intervals.first().unwrap().start_time,
+        intervals.last().unwrap().end_time,
+    )
+}
+
+pub fn hour(hours: i64) -> DateTime<Utc> {
+    sample_window().0 + Duration::hours(hours)
+}
+
+// Equal up to rounding, relative to the larger value. Takes f64 or Option<f64>, two
+// Nones are equal
+pub fn close(a: impl Into<Option<f64>>, b: impl Into<Option<f64>>) -> bool {
+    match (a.into(), b.into()) {
+        (Some(a), Some(b)) => (a - b).abs() <= 1e-9 * a.abs().max(b.abs()).max(1.0),
+        (a, b) => a == b,
+    }
+}
+
+// Query string params as the handlers get them
+pub fn params(value: serde_json::Value) -> RunepoolUnitsHistoryQueryParams {
+    serde_json::from_value(value).unwrap()
+}
+
+pub fn history_query(sort_field: SortField, sort_order: SortOrder) -> HistoryQuery {
+    let (start, end) = sample_window();
+    HistoryQuery {
+        limit: 400,
+        offset: 0,
+        start_time: Some(start),
+        end_time: Some(end),
+        units: ValueRange::default(),
+        count: ValueRange::default(),
+        sort_field,
+        sort_order,
+        after: None,
+    }
+}
+
+// The filters written out again rather than through HistoryQuery::matches, which the
+// KV stores filter with themselves: [start_time, end_time) and inclusive value bounds
+pub fn passes_filters(query: &HistoryQuery, interval: &RunepoolUnitsInterval) -> bool {
+    let within = |value: u64, range: ValueRange| {
+        range.min.is_none_or(|min| min <= value) && range.max.is_none_or(|max| value <= max)
+    };
+    query
+        .start_time
+        .is_none_or(|start| interval.start_time >= start)
+        && query.end_time.is_none_or(|end| interval.end_time <= end)
+        && within(interval.units, query.units)
+        && within(interval.count, query.count)
+}
+
+// Where (sorted value, start time) comes in the query order, ties go by start time
+// ascending like on every backend
+fn compare(
+    query: &HistoryQuery,
+    (a_value, a_start): (u64, DateTime<Utc>),
+    (b_value, b_start): (u64, DateTime<Utc>),
+) -> Ordering {
+    let ordering = match query.sort_field {
+        SortField::StartTime => a_start.cmp(&b_start),
+        SortField::Units | SortField::Count => a_value.cmp(&b_value),
+    };
+    let ordering = match query.sort_order {
+        SortOrder::Asc => ordering,
+        SortOrder::Desc => ordering.reverse(),
+    };
+    ordering.then(a_start.cmp(&b_start))
+}
+
+fn sort_key(query: &HistoryQuery, interval: &RunepoolUnitsInterval) -> (u64, DateTime<Utc>) {
+    let value = match query.sort_field {
+        SortField::StartTime => 0,
+        SortField::Units => interval.units,
+        SortField::Count => interval.count,
+    };
+    (value, interval.start_time)
+}
+
+// What every backend should return, worked out in plain rust
+pub fn expected(
+    intervals: &[RunepoolUnitsInterval],
+    query: &HistoryQuery,
+) -> Vec<RunepoolUnitsInterval> {
+    let mut matches: Vec<RunepoolUnitsInterval> = intervals
+        .iter()
+        .filter(|interval| passes_filters(query, interval))
+        .cloned()
+        .collect();
+    matches.sort_by(|a, b| compare(query, sort_key(query, a), sort_key(query, b)));
+    matches
+        .into_iter()
+        .filter(|interval| {
+            query.after.is_none_or(|cursor| {
+                let cursor_key = (cursor.value, cursor.start_time);
+                compare(query, cursor_key, sort_key(query, interval)) == Ordering::Less
+            })
+        })
+        .skip(query.offset as usize)
+        .take(query.limit as usize)
+        .collect()
+}
+
+pub fn spans(intervals: &[RunepoolUnitsInterval]) -> Vec<(i64, i64, u64, u64)> {
+    intervals
+        .iter()
+        .map(|interval| {
+            (
+                interval.start_time.timestamp(),
+                interval.end_time.timestamp(),
+                interval.units,
+                interval.count,
+            )
+        })
+        .collect()
+}
+
+// Replaces whatever is in the sample window with the sample intervals
+pub async fn seed<S: RunepoolStore>(store: &S) {
+    let (start, end) = sample_window();
+    store.delete_range(start, end).await.unwrap();
+    assert_eq!(
+        store.insert(&sample_intervals()).await.unwrap(),
+        SAMPLE_SIZE as usize
+    );
+}
+
+pub async fn cleanup<S: RunepoolStore>(store: &S) {
+    let (start, end) = sample_window();
+    store.delete_range(start, end).await.unwrap();
+}
+
+pub fn level_store() -> LevelStore {
+    let db = rusty_leveldb::DB::open("test", rusty_leveldb::in_memory()).unwrap();
+    LevelStore::new(Arc::new(Mutex::new(db)))
+}
+
+pub fn rocks_store() -> RocksStore {
+    let path = test_url("ROCKSDB_TEST_DATABASE_URL");
+    RocksStore::new(Arc::new(open_rocksdb(&path).unwrap()))
+}
+
+pub async fn postgres_store() -> PostgresStore {
+    let url = test_url("POSTGRES_TEST_DATABASE_URL");
+    let pool = sqlx::PgPool::connect(&url).await.unwrap();
+    PostgresStore::new(pool)
+}
+
+pub async fn mongo_store() -> MongoStore {
+    let url = test_url("MONGODB_TEST_DATABASE_URL");
+    let store = MongoStore::new(mongodb::Client::with_uri_str(&url).await.unwrap());
+    store.ensure_indexes().await.unwrap();
+    store
+}
+
+pub async fn surreal_store() -> SurrealStore {
+    let url = test_url("SURREAL_TEST_DATABASE_URL");
+    let db = Surreal::new::<Ws>(url).await.unwrap();
+    db.signin(Root {
+        username: "root",
+        password: "root",
+    })
+    .await
+    .unwrap();
+    db.use_ns("test").use_db("test").await.unwrap();
+
+    let store = SurrealStore::new(db);
+    store.migrate().await.unwrap();
+    store
+}
@@ -0,0 +1,164 @@
+// Deltas on every backend against the changes between neighbouring sample intervals
+mod common;
+
+use common::*;
+use db_tester::core::models::common::{HistoryCursor, SortField, SortOrder, ValueRange};
+use db_tester::core::models::runepool_units_history::RunepoolUnitsDelta;
+use db_tester::services::repository::{HistoryQuery, RunepoolStore};
+
+type Row = (
+    i64,
+    u64,
+    u64,
+    Option<i64>,
+    Option<f64>,
+    Option<i64>,
+    Option<f64>,
+);
+
+fn rows(deltas: &[RunepoolUnitsDelta]) -> Vec<Row> {
+    deltas
+        .iter()
+        .map(|delta| {
+            (
+                delta.interval.start_time.timestamp(),
+                delta.interval.units,
+                delta.interval.count,
+                delta.units_change,
+                delta.units_change_pct,
+                delta.count_change,
+                delta.count_change_pct,
+            )
+        })
+        .collect()
+}
+
+// Every sample interval is compared with the one an hour before it, even when that one
+// is outside the window. Nothing is stored before the sample
+fn expected_deltas(query: &HistoryQuery) -> Vec<RunepoolUnitsDelta> {
+    let intervals = sample_intervals();
+    let window = HistoryQuery {
+        units: ValueRange::default(),
+        count: ValueRange::default(),
+        ..query.clone()
+    };
+    let mut deltas: Vec<RunepoolUnitsDelta> = intervals
+        .iter()
+        .enumerate()
+        .filter(|(_, interval)| passes_filters(&window, interval))
+        .map(|(i, interval)| {
+            let previous = i.checked_sub(1).map(|i| &intervals[i]);
+            RunepoolUnitsDelta::new(
+                interval.clone(),
+                previous.map(|previous| previous.units),
+                previous.map(|previous| previous.count),
+            )
+        })
+        .collect();
+    if query.sort_order == SortOrder::Desc {
+        deltas.reverse();
+    }
+    deltas
+        .into_iter()
+        .skip(query.offset as usize)
+        .take(query.limit as usize)
+        .collect()
+}
+
+fn queries() -> Vec<HistoryQuery> {
+    let mut queries = Vec::new();
+    for (from, to) in [(0, 45), (3, 17), (9, 10), (44, 45), (20, 20)] {
+        for sort_order in [SortOrder::Asc, SortOrder::Desc] {
+            for (limit, offset) in [(400, 0), (3, 0), (3, 5)] {
+                queries.push(HistoryQuery {
+                    limit,
+                    offset,
+                    start_time: Some(hour(from)),
+                    end_time: Some(hour(to)),
+                    ..history_query(SortField::StartTime, sort_order)
+                });
+            }
+        }
+    }
+    // Units filters don't apply, the changes are always between stored neighbours
+    queries.push(HistoryQuery {
+        units: ValueRange::default().at_least(300),
+        ..history_query(SortField::StartTime, SortOrder::Asc)
+    });
+    queries
+}
+
+async fn check_deltas<S: RunepoolStore>(store: &S) {
+    seed(store).await;
+
+    for query in queries() {
+        assert_eq!(
+            rows(&store.deltas(&query).await.unwrap()),
+            rows(&expected_deltas(&query)),
+            "{} {:?}",
+            S::DATABASE_TYPE.name(),
+            query
+        );
+    }
+
+    // Paging with cursors goes through the same deltas as one big page
+    for sort_order in [SortOrder::Asc, SortOrder::Desc] {
+        let full = HistoryQuery {
+            start_time: Some(hour(2)),
+            end_time: Some(hour(30)),
+            ..history_query(SortField::StartTime, sort_order)
+        };
+        let mut paged = Vec::new();
+        let mut after = None;
+        loop {
+            let page = store
+                .deltas(&HistoryQuery {
+                    limit: 4,
+                    after,
+                    ..full.clone()
+                })
+                .await
+                .unwrap();
+            let Some(last) = page.last() else {
+                break;
+            };
+            after = Some(HistoryCursor::after(
+                &last.interval,
+                SortField::StartTime,
+                sort_order,
+            ));
+            paged.extend(page);
+        }
+        assert_eq!(
+            rows(&paged),
+            rows(&expected_deltas(&full)),
+            "{} {:?}",
+            S::DATABASE_TYPE.name(),
+            sort_order
+        );
+    }
+
+    cleanup(store).await;
+}
+
+#[test]
+fn change_from_zero_has_no_percentage() {
+    let interval = sample_intervals()[1].clone();
+    let delta = RunepoolUnitsDelta::new(interval.clone(), Some(0), Some(2));
+    assert_eq!(delta.units_change, Some(interval.units as i64));
+    assert_eq!(delta.units_change_pct, None);
+    assert_eq!(delta.count_change, Some(interval.count as i64 - 2));
+
+    let first = RunepoolUnitsDelta::new(interval, None, None);
+    assert_eq!((first.units_change, first.count_change), (None, None));
+
+    let delta = serde_json::to_value(&delta).unwrap();
+    assert_eq!(delta["unitsChange"], 200);
+    assert_eq!(delta["countChangePct"], -50.0);
+    assert_eq!(
+        delta["startTime"],
+        first.interval.start_time.timestamp().to_string()
+    );
+}
+
+backend_tests!(check_deltas);
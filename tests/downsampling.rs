@@ -0,0 +1,188 @@
+// Downsampling on every backend against LTTB run over the whole series in memory
+mod common;
+
+use chrono::{Duration, TimeZone, Utc};
+use common::*;
+use db_tester::core::models::common::{HistoryCursor, SortField, SortOrder, ValueRange};
+use db_tester::core::models::runepool_units_history::RunepoolUnitsInterval;
+use db_tester::services::repository::downsampling::downsample;
+use db_tester::services::repository::kv::{KvRange, KvScan};
+use db_tester::services::repository::{HistoryQuery, RunepoolStore};
+
+// Long enough to take several batches, well after the sample
+fn long_series() -> Vec<RunepoolUnitsInterval> {
+    let start = Utc.with_ymd_and_hms(2001, 3, 1, 0, 0, 0).unwrap();
+    (0..2500)
+        .map(|i| RunepoolUnitsInterval {
+            start_time: start + Duration::hours(i),
+            end_time: start + Duration::hours(i + 1),
+            count: (i % 5) as u64,
+            units: ((i * 7919) % 1000) as u64 * 10 + i as u64,
+        })
+        .collect()
+}
+
+// The usual LTTB with every bucket indexed from the start
+fn lttb(series: &[RunepoolUnitsInterval], points: usize) -> Vec<RunepoolUnitsInterval> {
+    let n = series.len();
+    if n <= points {
+        return series.to_vec();
+    }
+    let xy = |i: usize| {
+        (
+            series[i].start_time.timestamp() as f64,
+            series[i].units as f64,
+        )
+    };
+    let every = (n - 2) as f64 / (points - 2) as f64;
+    let start = |bucket: usize| (bucket as f64 * every).floor() as usize + 1;
+
+    let mut picked = vec![0];
+    for bucket in 0..points - 2 {
+        let next = start(bucket + 1)..start(bucket + 2).min(n);
+        let len = next.len() as f64;
+        let (sum_x, sum_y) = next
+            .map(xy)
+            .fold((0.0, 0.0), |(x, y), (px, py)| (x + px, y + py));
+        let (avg_x, avg_y) = (sum_x / len, sum_y / len);
+
+        let a = xy(*picked.last().unwrap());
+        let mut best = (-1.0, 0);
+        for i in start(bucket)..start(bucket + 1) {
+            let (x, y) = xy(i);
+            let area = ((a.0 - avg_x) * (y - a.1) - (a.0 - x) * (avg_y - a.1)).abs();
+            if area > best.0 {
+                best = (area, i);
+            }
+        }
+        picked.push(best.1);
+    }
+    picked.push(n - 1);
+    picked.into_iter().map(|i| series[i].clone()).collect()
+}
+
+fn expected_downsampled(
+    intervals: &[RunepoolUnitsInterval],
+    query: &HistoryQuery,
+    points: u32,
+) -> Vec<RunepoolUnitsInterval> {
+    let series: Vec<_> = intervals
+        .iter()
+        .filter(|interval| passes_filters(query, interval))
+        .cloned()
+        .collect();
+    let mut picked = lttb(&series, points as usize);
+    if query.sort_order == SortOrder::Desc {
+        picked.reverse();
+    }
+    picked
+}
+
+async fn check_downsampling<S: RunepoolStore>(store: &S) {
+    seed(store).await;
+
+    let any = ValueRange::default();
+    for (from, to, units) in [
+        (0, 45, any),
+        (5, 30, any.at_least(200)),
+        (10, 12, any),
+        (20, 20, any),
+    ] {
+        for sort_order in [SortOrder::Asc, SortOrder::Desc] {
+            let query = HistoryQuery {
+                // Paging doesn't apply to the downsampled series
+                limit: 4,
+                offset: 3,
+                start_time: Some(hour(from)),
+                end_time: Some(hour(to)),
+                units,
+                ..history_query(SortField::StartTime, sort_order)
+            };
+            for points in [3, 4, 10, 44, 45, 100] {
+                assert_eq!(
+                    spans(&downsample(store, &query, points).await.unwrap()),
+                    spans(&expected_downsampled(&sample_intervals(), &query, points)),
+                    "{} {} {:?}",
+                    S::DATABASE_TYPE.name(),
+                    points,
+                    query
+                );
+            }
+        }
+    }
+    assert!(downsample(
+        store,
+        &history_query(SortField::StartTime, SortOrder::Asc),
+        2
+    )
+    .await
+    .is_err());
+
+    let series = long_series();
+    let (start, end) = (
+        series.first().unwrap().start_time,
+        series.last().unwrap().end_time,
+    );
+    store.delete_range(start, end).await.unwrap();
+    store.insert(&series).await.unwrap();
+    for sort_order in [SortOrder::Asc, SortOrder::Desc] {
+        let query = HistoryQuery {
+            start_time: Some(start),
+            end_time: Some(end),
+            ..history_query(SortField::StartTime, sort_order)
+        };
+        for points in [3, 250, 999, 2000] {
+            let downsampled = downsample(store, &query, points).await.unwrap();
+            assert_eq!(downsampled.len(), points as usize);
+            assert_eq!(
+                spans(&downsampled),
+                spans(&expected_downsampled(&series, &query, points)),
+                "{} {} {:?}",
+                S::DATABASE_TYPE.name(),
+                points,
+                sort_order
+            );
+        }
+    }
+    store.delete_range(start, end).await.unwrap();
+
+    cleanup(store).await;
+}
+
+// Without a time range a units filter reads the units index, the batches after the
+// first one go by the primary keys from where the one before ended
+#[tokio::test]
+async fn units_filter_without_time_range() {
+    init();
+    let store = level_store();
+    let series = long_series();
+    store.insert(&series).await.unwrap();
+
+    let query = HistoryQuery {
+        start_time: None,
+        end_time: None,
+        units: ValueRange::default().at_least(5000),
+        ..history_query(SortField::StartTime, SortOrder::Asc)
+    };
+    assert_eq!(KvRange::for_query(&query).scan, KvScan::Units);
+    let after = HistoryQuery {
+        after: Some(HistoryCursor::after(
+            &series[0],
+            SortField::StartTime,
+            SortOrder::Asc,
+        )),
+        ..query.clone()
+    };
+    assert_eq!(KvRange::for_query(&after).scan, KvScan::Time);
+
+    for points in [3, 500, 1200] {
+        assert_eq!(
+            spans(&downsample(&store, &query, points).await.unwrap()),
+            spans(&expected_downsampled(&series, &query, points)),
+            "{}",
+            points
+        );
+    }
+}
+
+backend_tests!(check_downsampling);
@@ -0,0 +1,234 @@
+// Gap filling on every backend against a grid worked out over the stored intervals,
+// with hours taken out of the sample
+mod common;
+
+use chrono::Duration;
+use common::*;
+use db_tester::core::models::common::{
+    CountMode, Fill, HistoryCursor, Interval, SortField, SortOrder,
+};
+use db_tester::core::models::runepool_units_history::{
+    FilledRunepoolUnitsInterval, RunepoolUnitsInterval,
+};
+use db_tester::services::repository::filling::{fill, TooManySlots};
+use db_tester::services::repository::{HistoryQuery, Resample, RunepoolStore};
+
+type Row = (i64, i64, Option<u64>, Option<u64>, bool);
+
+fn rows(filled: &[FilledRunepoolUnitsInterval]) -> Vec<Row> {
+    filled
+        .iter()
+        .map(|slot| {
+            (
+                slot.start_time.timestamp(),
+                slot.end_time.timestamp(),
+                slot.count,
+                slot.units,
+                slot.filled,
+            )
+        })
+        .collect()
+}
+
+// Hours 5 to 7, 20 and 31 gone
+fn missing(interval: &RunepoolUnitsInterval) -> bool {
+    let start = interval.start_time;
+    (start >= hour(5) && start < hour(8)) || start == hour(20) || start == hour(31)
+}
+
+// Every hour from the first stored interval in range to the last one
+fn expected_filled(query: &HistoryQuery, fill: Fill) -> Vec<Row> {
+    let stored: Vec<_> = sample_intervals()
+        .into_iter()
+        .filter(|interval| !missing(interval) && passes_filters(query, interval))
+        .collect();
+
+    let mut grid = Vec::new();
+    for (i, interval) in stored.iter().enumerate() {
+        if let Some(previous) = i.checked_sub(1).map(|i| &stored[i]) {
+            let hours = (interval.start_time - previous.start_time).num_hours();
+            for step in 1..hours {
+                let start = previous.start_time + Duration::hours(step);
+                let (count, units) = match fill {
+                    Fill::None => continue,
+                    Fill::Null => (None, None),
+                    Fill::Previous => (Some(previous.count), Some(previous.units)),
+                    Fill::Linear => {
+                        let line = |from: u64, to: u64| {
+                            let fraction = step as f64 / hours as f64;
+                            (from as f64 + (to as f64 - from as f64) * fraction).round() as u64
+                        };
+                        (
+                            Some(line(previous.count, interval.count)),
+                            Some(line(previous.units, interval.units)),
+                        )
+                    }
+                };
+                grid.push((
+                    start.timestamp(),
+                    (start + Duration::hours(1)).timestamp(),
+                    count,
+                    units,
+                    true,
+                ));
+            }
+        }
+        grid.push((
+            interval.start_time.timestamp(),
+            interval.end_time.timestamp(),
+            Some(interval.count),
+            Some(interval.units),
+            false,
+        ));
+    }
+
+    if query.sort_order == SortOrder::Desc {
+        grid.reverse();
+    }
+    grid
+}
+
+fn window(from: i64, to: i64, sort_order: SortOrder) -> HistoryQuery {
+    HistoryQuery {
+        start_time: Some(hour(from)),
+        end_time: Some(hour(to)),
+        ..history_query(SortField::StartTime, sort_order)
+    }
+}
+
+async fn check_filling<S: RunepoolStore>(store: &S) {
+    seed(store).await;
+    for (from, to) in [(5, 8), (20, 21), (31, 32)] {
+        store.delete_range(hour(from), hour(to)).await.unwrap();
+    }
+
+    // Hour buckets of the hourly sample are the sample itself, so both get the same grid
+    let hourly = Resample {
+        interval: Interval::Hour,
+        count_mode: CountMode::Last,
+    };
+    for fill_mode in [Fill::None, Fill::Null, Fill::Previous, Fill::Linear] {
+        for sort_order in [SortOrder::Asc, SortOrder::Desc] {
+            for (from, to) in [(0, 45), (3, 22), (6, 7)] {
+                let query = window(from, to, sort_order);
+                let expected = expected_filled(&query, fill_mode);
+                for resample in [None, Some(hourly)] {
+                    let page = match resample {
+                        Some(resample) => store.resample(&query, resample).await.unwrap(),
+                        None => store.query(&query).await.unwrap(),
+                    };
+                    let filled = fill(store, &query, resample, page, fill_mode)
+                        .await
+                        .unwrap();
+                    assert_eq!(
+                        rows(&filled),
+                        expected,
+                        "{} {:?} {:?} {:?}",
+                        S::DATABASE_TYPE.name(),
+                        fill_mode,
+                        resample,
+                        query
+                    );
+                }
+            }
+        }
+    }
+
+    // Pages by cursor, and buckets by offset, put together make the same grid with every
+    // missing slot once
+    for sort_order in [SortOrder::Asc, SortOrder::Desc] {
+        let full = window(0, 45, sort_order);
+        let expected = expected_filled(&full, Fill::Linear);
+
+        let mut paged = Vec::new();
+        let mut after = None;
+        loop {
+            let query = HistoryQuery {
+                limit: 4,
+                after,
+                ..full.clone()
+            };
+            let page = store.query(&query).await.unwrap();
+            let Some(last) = page.last() else {
+                break;
+            };
+            after = Some(HistoryCursor::after(last, SortField::StartTime, sort_order));
+            paged.extend(fill(store, &query, None, page, Fill::Linear).await.unwrap());
+        }
+        assert_eq!(rows(&paged), expected, "{}", S::DATABASE_TYPE.name());
+
+        let mut paged = Vec::new();
+        for offset in (0..45).step_by(4) {
+            let query = HistoryQuery {
+                limit: 4,
+                offset,
+                ..full.clone()
+            };
+            let page = store.resample(&query, hourly).await.unwrap();
+            paged.extend(
+                fill(store, &query, Some(hourly), page, Fill::Linear)
+                    .await
+                    .unwrap(),
+            );
+        }
+        assert_eq!(rows(&paged), expected, "{}", S::DATABASE_TYPE.name());
+    }
+
+    // Weeks of 5 minute buckets to fill are too many for one response
+    let far = hour(45 + 24 * 60);
+    let lone = RunepoolUnitsInterval {
+        start_time: far,
+        end_time: far + Duration::hours(1),
+        count: 1,
+        units: 100,
+    };
+    store.insert(&[lone]).await.unwrap();
+    let query = HistoryQuery {
+        end_time: Some(far + Duration::hours(1)),
+        ..window(40, 45, SortOrder::Asc)
+    };
+    let five_min = Resample {
+        interval: Interval::FiveMin,
+        count_mode: CountMode::Last,
+    };
+    let page = store.resample(&query, five_min).await.unwrap();
+    let error = fill(store, &query, Some(five_min), page, Fill::Null)
+        .await
+        .unwrap_err();
+    assert!(error.is::<TooManySlots>(), "{}", S::DATABASE_TYPE.name());
+    let page = store.query(&query).await.unwrap();
+    let filled = fill(store, &query, None, page, Fill::Null).await.unwrap();
+    assert_eq!(filled.len(), 5 + 24 * 60 + 1);
+    store
+        .delete_range(far, far + Duration::hours(1))
+        .await
+        .unwrap();
+
+    cleanup(store).await;
+}
+
+#[test]
+fn only_filled_slots_are_flagged() {
+    let stored = FilledRunepoolUnitsInterval::from(sample_intervals()[1].clone());
+    let stored = serde_json::to_value(&stored).unwrap();
+    assert_eq!(stored["units"], "200");
+    assert!(stored.get("filled").is_none());
+
+    let filled = FilledRunepoolUnitsInterval {
+        count: None,
+        units: None,
+        filled: true,
+        ..FilledRunepoolUnitsInterval::from(sample_intervals()[1].clone())
+    };
+    let filled = serde_json::to_value(&filled).unwrap();
+    assert_eq!(
+        (&filled["units"], &filled["count"], &filled["filled"]),
+        (
+            &serde_json::Value::Null,
+            &serde_json::Value::Null,
+            &true.into()
+        )
+    );
+}
+
+backend_tests!(check_filling);
@@ -0,0 +1,144 @@
+// Units and count filters on every backend, each combination against the rows picked
+// out by hand from the sample intervals
+mod common;
+
+use common::*;
+use db_tester::core::models::common::{SortField, SortOrder, ValueRange};
+use db_tester::services::repository::{HistoryQuery, RunepoolStore};
+use serde_json::json;
+
+#[test]
+fn comparisons_narrow_one_range() {
+    let units = params(json!({ "units_gt": 100, "units_lte": 500, "units_gte": 50 }))
+        .parse_units_range()
+        .unwrap();
+    assert_eq!((units.min, units.max), (Some(101), Some(500)));
+
+    let units = params(json!({ "units_between": "200,400", "units_lt": 300 }))
+        .parse_units_range()
+        .unwrap();
+    assert_eq!((units.min, units.max), (Some(200), Some(299)));
+
+    let count = params(json!({ "count_gte": 1, "count_lt": 3 })).count_range();
+    assert_eq!((count.min, count.max), (Some(1), Some(2)));
+
+    // Nothing is below zero
+    assert!(!params(json!({ "count_lt": 0 })).count_range().contains(0));
+}
+
+#[test]
+fn rejects_bad_units_between() {
+    for between in ["300", "300,200", "a,b", "1,2,3"] {
+        assert!(
+            params(json!({ "units_between": between }))
+                .parse_units_range()
+                .is_err(),
+            "{}",
+            between
+        );
+    }
+}
+
+// (units, count) filters: single comparisons, both fields together, empty ranges
+fn filters() -> Vec<(ValueRange, ValueRange)> {
+    let any = ValueRange::default();
+    vec![
+        (any.greater_than(300), any),
+        (any.at_least(300), any),
+        (any.less_than(300), any),
+        (any.at_most(300), any),
+        (any.at_least(200).at_most(400), any),
+        (any, any.greater_than(1)),
+        (any, any.at_least(1)),
+        (any, any.less_than(2)),
+        (any, any.at_most(2)),
+        (any.at_least(100).at_most(500), any.at_least(1).at_most(2)),
+        (any.greater_than(600), any),
+        (any.less_than(0), any),
+        (any.at_least(400).at_most(300), any),
+        // Bounds past what Postgres, MongoDB and SurrealDB can store
+        (any.at_most(u64::MAX), any.at_most(u64::MAX)),
+        (any.greater_than(i64::MAX as u64), any),
+        (any, any.at_least(u64::MAX)),
+    ]
+}
+
+async fn check_filters<S: RunepoolStore>(store: &S) {
+    seed(store).await;
+    let intervals = sample_intervals();
+
+    for (units, count) in filters() {
+        for sort_field in [SortField::StartTime, SortField::Units, SortField::Count] {
+            for sort_order in [SortOrder::Asc, SortOrder::Desc] {
+                let query = HistoryQuery {
+                    units,
+                    count,
+                    ..history_query(sort_field, sort_order)
+                };
+                let matching: Vec<_> = intervals
+                    .iter()
+                    .filter(|interval| {
+                        units.min.is_none_or(|min| interval.units >= min)
+                            && units.max.is_none_or(|max| interval.units <= max)
+                            && count.min.is_none_or(|min| interval.count >= min)
+                            && count.max.is_none_or(|max| interval.count <= max)
+                    })
+                    .cloned()
+                    .collect();
+
+                let results = store.query(&query).await.unwrap();
+                assert_eq!(
+                    spans(&results),
+                    spans(&expected(&matching, &query)),
+                    "{} {:?}",
+                    S::DATABASE_TYPE.name(),
+                    query
+                );
+                assert_eq!(
+                    store.count(&query).await.unwrap(),
+                    matching.len() as u64,
+                    "{} count {:?}",
+                    S::DATABASE_TYPE.name(),
+                    query
+                );
+            }
+        }
+    }
+
+    // Without a time range the filters alone pick the rows
+    for (units, count) in filters() {
+        let query = HistoryQuery {
+            start_time: None,
+            end_time: None,
+            units,
+            count,
+            ..history_query(SortField::StartTime, SortOrder::Asc)
+        };
+        let (window_start, window_end) = sample_window();
+        let results: Vec<_> = store
+            .query(&query)
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|interval| {
+                interval.start_time >= window_start && interval.end_time <= window_end
+            })
+            .collect();
+        let bounded = HistoryQuery {
+            start_time: Some(window_start),
+            end_time: Some(window_end),
+            ..query.clone()
+        };
+        assert_eq!(
+            spans(&results),
+            spans(&expected(&intervals, &bounded)),
+            "{} {:?}",
+            S::DATABASE_TYPE.name(),
+            query
+        );
+    }
+
+    cleanup(store).await;
+}
+
+backend_tests!(check_filters);
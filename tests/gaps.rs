@@ -0,0 +1,179 @@
+// Gap reports on every backend against neighbours compared over the stored intervals,
+// with hours taken out of the sample and intervals overlapping it added
+mod common;
+
+use chrono::{DateTime, Duration, Utc};
+use common::*;
+use db_tester::core::models::common::{SortField, SortOrder};
+use db_tester::core::models::runepool_units_history::{
+    IntervalBreak, RunepoolUnitsGaps, RunepoolUnitsInterval, TimeSpan,
+};
+use db_tester::services::repository::{HistoryQuery, RunepoolStore};
+
+fn minutes(minutes: i64) -> DateTime<Utc> {
+    sample_window().0 + Duration::minutes(minutes)
+}
+
+fn interval(start_time: DateTime<Utc>, end_time: DateTime<Utc>) -> RunepoolUnitsInterval {
+    RunepoolUnitsInterval {
+        start_time,
+        end_time,
+        count: 1,
+        units: 100,
+    }
+}
+
+// Hours 5 to 7 and the last one gone, one interval across hours 10 and 11 and one
+// starting with hour 20
+fn stored() -> Vec<RunepoolUnitsInterval> {
+    let mut intervals: Vec<_> = sample_intervals()
+        .into_iter()
+        .filter(|interval| {
+            !(interval.start_time >= hour(5) && interval.start_time < hour(8))
+                && interval.start_time != hour(44)
+        })
+        .collect();
+    intervals.push(interval(minutes(630), minutes(690)));
+    intervals.push(interval(hour(20), minutes(1230)));
+    intervals.sort_by_key(|interval| (interval.start_time, interval.end_time));
+    intervals
+}
+
+fn expected_gaps(query: &HistoryQuery) -> Option<RunepoolUnitsGaps> {
+    let intervals: Vec<_> = stored()
+        .into_iter()
+        .filter(|interval| passes_filters(query, interval))
+        .collect();
+    let breaks: Vec<_> = intervals
+        .windows(2)
+        .filter(|pair| {
+            pair[0].end_time != pair[1].start_time || pair[0].start_time == pair[1].start_time
+        })
+        .map(|pair| IntervalBreak {
+            previous_start: pair[0].start_time,
+            previous_end: pair[0].end_time,
+            start_time: pair[1].start_time,
+            end_time: pair[1].end_time,
+        })
+        .collect();
+
+    Some(RunepoolUnitsGaps::new(
+        intervals.first()?.start_time,
+        intervals.last()?.end_time,
+        intervals.len() as u64,
+        breaks,
+    ))
+}
+
+type Report = (
+    i64,
+    i64,
+    u64,
+    Vec<TimeSpan>,
+    Vec<TimeSpan>,
+    Vec<TimeSpan>,
+    i64,
+    f64,
+);
+
+fn report(gaps: Option<RunepoolUnitsGaps>) -> Option<Report> {
+    gaps.map(|gaps| {
+        (
+            gaps.start_time.timestamp(),
+            gaps.end_time.timestamp(),
+            gaps.intervals,
+            gaps.gaps,
+            gaps.overlaps,
+            gaps.duplicates,
+            gaps.missing_seconds,
+            gaps.coverage_pct,
+        )
+    })
+}
+
+fn time_range(from: i64, to: i64) -> HistoryQuery {
+    HistoryQuery {
+        start_time: Some(hour(from)),
+        end_time: Some(hour(to)),
+        ..history_query(SortField::StartTime, SortOrder::Asc)
+    }
+}
+
+async fn check_gaps<S: RunepoolStore>(store: &S) {
+    seed(store).await;
+    store.delete_range(hour(5), hour(8)).await.unwrap();
+    store.delete_range(hour(44), hour(45)).await.unwrap();
+    store
+        .insert(&[
+            interval(minutes(630), minutes(690)),
+            interval(hour(20), minutes(1230)),
+        ])
+        .await
+        .unwrap();
+
+    let gaps = store.gaps(&time_range(0, 45)).await.unwrap().unwrap();
+    assert_eq!(gaps.intervals, 43);
+    assert_eq!(
+        (gaps.start_time, gaps.end_time),
+        (hour(0), hour(44)),
+        "{}",
+        S::DATABASE_TYPE.name()
+    );
+    assert_eq!(gaps.gaps, vec![TimeSpan::new(hour(5), hour(8))]);
+    assert_eq!(
+        gaps.overlaps,
+        vec![
+            TimeSpan::new(minutes(630), hour(11)),
+            TimeSpan::new(hour(11), minutes(690)),
+        ]
+    );
+    assert_eq!(
+        gaps.duplicates,
+        vec![TimeSpan::new(hour(20), minutes(1230))]
+    );
+    assert_eq!(gaps.missing_seconds, 3 * 3600);
+    assert!((gaps.coverage_pct - 41.0 / 44.0 * 100.0).abs() < 1e-9);
+
+    for (from, to) in [
+        (0, 45),
+        (0, 5),
+        (4, 12),
+        (6, 8),
+        (9, 25),
+        (20, 21),
+        (44, 45),
+    ] {
+        let query = time_range(from, to);
+        assert_eq!(
+            report(store.gaps(&query).await.unwrap()),
+            report(expected_gaps(&query)),
+            "{} {} {}",
+            S::DATABASE_TYPE.name(),
+            from,
+            to
+        );
+    }
+
+    cleanup(store).await;
+    assert!(store.gaps(&time_range(0, 45)).await.unwrap().is_none());
+}
+
+#[test]
+fn contiguous_intervals_have_full_coverage() {
+    let gaps = RunepoolUnitsGaps::new(hour(0), hour(45), 45, Vec::new());
+    assert!(gaps.gaps.is_empty() && gaps.overlaps.is_empty() && gaps.duplicates.is_empty());
+    assert_eq!((gaps.missing_seconds, gaps.coverage_pct), (0, 100.0));
+
+    let gaps = serde_json::to_value(&gaps).unwrap();
+    assert_eq!(gaps["coveragePct"], 100.0);
+    assert_eq!(gaps["startTime"], hour(0).timestamp().to_string());
+}
+
+#[test]
+fn time_span_fields_are_camel_case() {
+    let span = serde_json::to_value(TimeSpan::new(hour(5), hour(8))).unwrap();
+    assert_eq!(span["endTime"], hour(8).timestamp().to_string());
+    assert_eq!(span["seconds"], 3 * 3600);
+}
+
+backend_tests!(check_gaps);
@@ -0,0 +1,110 @@
+// Requests the history handler turns away with a 400, sent through the router of the
+// in-memory LevelDB, next to requests it answers to show the params work on their own
+mod common;
+
+use axum::body::{to_bytes, Body};
+use axum::http::{Request, StatusCode};
+use common::*;
+use db_tester::api::routes::runepool::runepool_routes;
+use db_tester::core::models::common::{
+    HistoryCursor, SortField, SortOrder, MAX_POINTS, MAX_SMOOTHING_WINDOW,
+};
+use tower::ServiceExt;
+
+async fn get(uri: &str) -> (StatusCode, serde_json::Value) {
+    let store = level_store();
+    seed(&store).await;
+    let response = runepool_routes(Some(store))
+        .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let status = response.status();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    (status, serde_json::from_slice(&body).unwrap())
+}
+
+fn cursor(sort_field: SortField, sort_order: SortOrder) -> String {
+    HistoryCursor::after(&sample_intervals()[3], sort_field, sort_order).encode()
+}
+
+#[tokio::test]
+async fn history_rejects_conflicting_params() {
+    init();
+    let time_cursor = cursor(SortField::StartTime, SortOrder::Asc);
+    let units_cursor = cursor(SortField::Units, SortOrder::Asc);
+
+    for uri in [
+        // Buckets only page by page/limit
+        format!("/?interval=hour&cursor={}", time_cursor),
+        "/?interval=hour&include_total=true".to_string(),
+        // The cursor was issued for another sort
+        format!("/?sort_by=timestamp&cursor={}", units_cursor),
+        format!("/?sort_by=units&order=desc&cursor={}", units_cursor),
+        "/?cursor=zz".to_string(),
+        // points out of range or mixed with another mode
+        "/?points=2".to_string(),
+        format!("/?points={}", MAX_POINTS + 1),
+        format!("/?points=10&cursor={}", time_cursor),
+        "/?points=10&include_total=true".to_string(),
+        "/?points=10&interval=hour".to_string(),
+        "/?points=10&sma=3".to_string(),
+        "/?points=10&ema=3".to_string(),
+        "/?points=10&sort_by=units".to_string(),
+        // Smoothing windows past what a page can warm up with
+        "/?sma=0".to_string(),
+        format!("/?sma={}", MAX_SMOOTHING_WINDOW + 1),
+        format!("/?ema={}", u32::MAX),
+        "/?sma=3&sort_by=units".to_string(),
+        // fill can't tell filtered out slots from missing ones
+        "/?fill=linear&units_gt=100".to_string(),
+        "/?fill=null&count_lte=2".to_string(),
+        "/?fill=previous&points=10".to_string(),
+        "/?fill=linear&sma=3".to_string(),
+        "/?fill=linear&ema=3".to_string(),
+        "/?fill=null&sort_by=units".to_string(),
+        // Windows ending before they start
+        "/?from=2001-01-02&to=2001-01-01".to_string(),
+        "/?from=978307300&to=978307200".to_string(),
+    ] {
+        let (status, body) = get(&uri).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{} {}", uri, body);
+        assert_eq!(body["success"], false, "{}", uri);
+        assert!(body["error"].is_string(), "{}", uri);
+    }
+}
+
+#[tokio::test]
+async fn history_accepts_each_mode_alone() {
+    init();
+    for uri in [
+        format!("/?cursor={}", cursor(SortField::StartTime, SortOrder::Asc)),
+        format!(
+            "/?sort_by=units&cursor={}",
+            cursor(SortField::Units, SortOrder::Asc)
+        ),
+        "/?interval=hour".to_string(),
+        "/?include_total=true&units_gt=100".to_string(),
+        "/?points=3".to_string(),
+        format!("/?points={}", MAX_POINTS),
+        "/?sma=3&ema=3".to_string(),
+        format!(
+            "/?sma={}&ema={}",
+            MAX_SMOOTHING_WINDOW, MAX_SMOOTHING_WINDOW
+        ),
+        "/?fill=linear&interval=hour".to_string(),
+        "/?from=2001-01-01&to=2001-01-02".to_string(),
+    ] {
+        let (status, body) = get(&uri).await;
+        assert_eq!(status, StatusCode::OK, "{} {}", uri, body);
+        assert!(body["intervals"].is_array(), "{} {}", uri, body);
+    }
+}
+
+#[tokio::test]
+async fn smoothed_fields_are_camel_case() {
+    init();
+    let (_, body) = get("/?sma=3&ema=3").await;
+    let first = &body["intervals"][0];
+    assert!(first["unitsSma"].is_number(), "{}", first);
+    assert!(first["unitsEma"].is_number(), "{}", first);
+}
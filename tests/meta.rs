@@ -0,0 +1,110 @@
+// Meta covers the whole filtered range on every backend, whatever page or order is
+// requested, and agrees with the meta Midgard itself returns
+mod common;
+
+use common::*;
+use db_tester::api::server::runepool_units_history::fetch_runepool_units_history;
+use db_tester::core::models::common::{Interval, SortField, SortOrder, ValueRange};
+use db_tester::core::models::runepool_units_history::{MetaStats, RunepoolUnitsHistoryParams};
+use db_tester::services::repository::{HistoryQuery, RunepoolStore};
+
+fn expected_meta(query: &HistoryQuery) -> Option<MetaStats> {
+    let matching: Vec<_> = sample_intervals()
+        .into_iter()
+        .filter(|interval| passes_filters(query, interval))
+        .collect();
+    Some(MetaStats::new(matching.first()?, matching.last()?))
+}
+
+fn queries() -> Vec<HistoryQuery> {
+    let any = ValueRange::default();
+    let window = |from: i64, to: i64, units: ValueRange| HistoryQuery {
+        start_time: Some(hour(from)),
+        end_time: Some(hour(to)),
+        units,
+        ..history_query(SortField::StartTime, SortOrder::Asc)
+    };
+
+    let mut queries = Vec::new();
+    for (from, to, units) in [
+        (0, 45, any),
+        (3, 17, any),
+        // The first and last hours of the window don't pass the filter
+        (0, 45, any.at_least(300)),
+        (9, 10, any),
+        (0, 45, any.greater_than(600)),
+    ] {
+        for sort_field in [SortField::StartTime, SortField::Units, SortField::Count] {
+            for sort_order in [SortOrder::Asc, SortOrder::Desc] {
+                for (limit, offset) in [(400, 0), (3, 0), (3, 6)] {
+                    queries.push(HistoryQuery {
+                        limit,
+                        offset,
+                        sort_field,
+                        sort_order,
+                        ..window(from, to, units)
+                    });
+                }
+            }
+        }
+    }
+    queries
+}
+
+async fn check_meta<S: RunepoolStore>(store: &S) {
+    seed(store).await;
+
+    for query in queries() {
+        assert_eq!(
+            store.meta(&query).await.unwrap(),
+            expected_meta(&query),
+            "{} {:?}",
+            S::DATABASE_TYPE.name(),
+            query
+        );
+    }
+
+    cleanup(store).await;
+}
+
+backend_tests!(check_meta);
+
+// Stores a real Midgard response in the in-memory LevelDB and reads the meta back
+// one page at a time, against the Midgard /v2 api MIDGARD_TEST_API_URL points at
+#[tokio::test]
+#[ignore = "needs MIDGARD_TEST_API_URL"]
+async fn meta_matches_midgard() {
+    init();
+    let base_url = test_url("MIDGARD_TEST_API_URL");
+
+    let params = RunepoolUnitsHistoryParams {
+        interval: Some(Interval::Hour),
+        count: Some(48),
+        from: None,
+        to: None,
+    };
+    let response = fetch_runepool_units_history(&base_url, &params)
+        .await
+        .unwrap();
+    let store = level_store();
+    store.insert(&response.intervals).await.unwrap();
+
+    let midgard_meta = response.meta_stats;
+    for sort_order in [SortOrder::Asc, SortOrder::Desc] {
+        for offset in [0, 10, 40] {
+            let query = HistoryQuery {
+                limit: 5,
+                offset,
+                start_time: Some(midgard_meta.start_time),
+                end_time: Some(midgard_meta.end_time),
+                ..history_query(SortField::StartTime, sort_order)
+            };
+            assert_eq!(
+                store.meta(&query).await.unwrap().as_ref(),
+                Some(&midgard_meta),
+                "{:?}",
+                query
+            );
+        }
+    }
+}
@@ -0,0 +1,148 @@
+// Walks every page of the same queries on each backend and checks the pages against
+// the reference ordering, so page N is the same on every endpoint
+mod common;
+
+use common::*;
+use db_tester::core::models::common::{HistoryCursor, SortField, SortOrder, ValueRange};
+use db_tester::core::models::runepool_units_history::{
+    MetaStats, PaginationMeta, RunepoolUnitsHistoryResponse,
+};
+use db_tester::services::repository::{HistoryQuery, RunepoolStore};
+
+const PAGE_SIZE: u32 = 7;
+
+fn queries() -> Vec<HistoryQuery> {
+    let (start, _) = sample_window();
+    let mut queries = Vec::new();
+    for sort_field in [SortField::StartTime, SortField::Units, SortField::Count] {
+        for sort_order in [SortOrder::Asc, SortOrder::Desc] {
+            let query = history_query(sort_field, sort_order);
+            queries.push(HistoryQuery {
+                units: ValueRange::default().greater_than(200),
+                ..query.clone()
+            });
+            queries.push(HistoryQuery {
+                end_time: Some(start + chrono::Duration::hours(20)),
+                ..query.clone()
+            });
+            queries.push(query);
+        }
+    }
+    queries
+}
+
+async fn check_pages<S: RunepoolStore>(store: &S) {
+    seed(store).await;
+    let intervals = sample_intervals();
+
+    for query in queries() {
+        let all = expected(&intervals, &query);
+        let mut seen = Vec::new();
+
+        for page in 0.. {
+            let page_query = HistoryQuery {
+                limit: PAGE_SIZE,
+                offset: page * PAGE_SIZE,
+                ..query.clone()
+            };
+            let results = store.query(&page_query).await.unwrap();
+            assert_eq!(
+                spans(&results),
+                spans(&expected(&intervals, &page_query)),
+                "{} page {} of {:?}",
+                S::DATABASE_TYPE.name(),
+                page,
+                query
+            );
+
+            if results.is_empty() {
+                break;
+            }
+            seen.extend(results);
+        }
+
+        assert_eq!(spans(&seen), spans(&all), "{:?}", query);
+        assert_eq!(store.count(&query).await.unwrap(), all.len() as u64);
+
+        // Following the cursors visits the same intervals in the same order
+        let mut seen = Vec::new();
+        let mut after = None;
+        loop {
+            let page_query = HistoryQuery {
+                limit: PAGE_SIZE,
+                after,
+                ..query.clone()
+            };
+            let results = store.query(&page_query).await.unwrap();
+            assert_eq!(
+                store.count(&page_query).await.unwrap(),
+                all.len() as u64,
+                "count ignores the cursor"
+            );
+
+            let Some(last) = results.last() else {
+                break;
+            };
+            // Go through the opaque form like a client would
+            let cursor = HistoryCursor::after(last, query.sort_field, query.sort_order).encode();
+            after = Some(HistoryCursor::decode(&cursor).unwrap());
+            seen.extend(results);
+        }
+        assert_eq!(
+            spans(&seen),
+            spans(&all),
+            "{} cursor pages of {:?}",
+            S::DATABASE_TYPE.name(),
+            query
+        );
+    }
+
+    // A cursor past every value the databases can store, nothing comes after it going
+    // up and everything going down
+    for sort_field in [SortField::Units, SortField::Count] {
+        for sort_order in [SortOrder::Asc, SortOrder::Desc] {
+            let query = HistoryQuery {
+                after: Some(HistoryCursor {
+                    sort_field,
+                    sort_order,
+                    value: u64::MAX,
+                    start_time: sample_window().0,
+                }),
+                ..history_query(sort_field, sort_order)
+            };
+            assert_eq!(
+                spans(&store.query(&query).await.unwrap()),
+                spans(&expected(&intervals, &query)),
+                "{} {:?}",
+                S::DATABASE_TYPE.name(),
+                query
+            );
+        }
+    }
+
+    cleanup(store).await;
+}
+
+#[test]
+fn pagination_fields_are_camel_case() {
+    let intervals = sample_intervals();
+    let response = RunepoolUnitsHistoryResponse {
+        meta_stats: MetaStats::new(&intervals[0], &intervals[1]),
+        intervals,
+        next_cursor: Some("cursor".to_string()),
+        pagination: Some(PaginationMeta {
+            total: 45,
+            page: Some(0),
+            limit: 30,
+            has_more: true,
+            prev: None,
+            next: None,
+        }),
+    };
+    let response = serde_json::to_value(&response).unwrap();
+    assert_eq!(response["nextCursor"], "cursor");
+    assert_eq!(response["pagination"]["hasMore"], true);
+    assert!(response.get("next_cursor").is_none());
+}
+
+backend_tests!(check_pages);
@@ -0,0 +1,200 @@
+// Resampling into coarser buckets on every backend against buckets folded in plain rust
+mod common;
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use common::*;
+use db_tester::core::models::common::{CountMode, Interval, SortField, SortOrder, ValueRange};
+use db_tester::core::models::runepool_units_history::RunepoolUnitsInterval;
+use db_tester::services::repository::{HistoryQuery, Resample, RunepoolStore};
+use std::collections::BTreeMap;
+
+const INTERVALS: [Interval; 7] = [
+    Interval::FiveMin,
+    Interval::Hour,
+    Interval::Day,
+    Interval::Week,
+    Interval::Month,
+    Interval::Quarter,
+    Interval::Year,
+];
+
+fn utc(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(year, month, day, hour, minute, 0)
+        .unwrap()
+}
+
+#[test]
+fn buckets_follow_calendar_boundaries() {
+    // A wednesday in the middle of the second quarter
+    let time = utc(2024, 5, 15, 13, 47);
+    let cases = [
+        (
+            Interval::FiveMin,
+            utc(2024, 5, 15, 13, 45),
+            utc(2024, 5, 15, 13, 50),
+        ),
+        (
+            Interval::Hour,
+            utc(2024, 5, 15, 13, 0),
+            utc(2024, 5, 15, 14, 0),
+        ),
+        (
+            Interval::Day,
+            utc(2024, 5, 15, 0, 0),
+            utc(2024, 5, 16, 0, 0),
+        ),
+        (
+            Interval::Week,
+            utc(2024, 5, 13, 0, 0),
+            utc(2024, 5, 20, 0, 0),
+        ),
+        (
+            Interval::Month,
+            utc(2024, 5, 1, 0, 0),
+            utc(2024, 6, 1, 0, 0),
+        ),
+        (
+            Interval::Quarter,
+            utc(2024, 4, 1, 0, 0),
+            utc(2024, 7, 1, 0, 0),
+        ),
+        (Interval::Year, utc(2024, 1, 1, 0, 0), utc(2025, 1, 1, 0, 0)),
+    ];
+
+    for (interval, start, end) in cases {
+        assert_eq!(interval.bucket_start(time), start, "{}", interval);
+        assert_eq!(interval.bucket_end(start), end, "{}", interval);
+        assert_eq!(interval.bucket_start(start), start, "{}", interval);
+    }
+
+    // Sunday still belongs to the week that started on monday
+    assert_eq!(
+        Interval::Week.bucket_start(utc(2024, 5, 19, 23, 59)),
+        utc(2024, 5, 13, 0, 0)
+    );
+}
+
+// Every matching interval goes into its bucket, the latest one gives the units
+fn expected_buckets(
+    intervals: &[RunepoolUnitsInterval],
+    query: &HistoryQuery,
+    resample: Resample,
+) -> Vec<RunepoolUnitsInterval> {
+    let mut grouped: BTreeMap<DateTime<Utc>, Vec<&RunepoolUnitsInterval>> = BTreeMap::new();
+    for interval in intervals
+        .iter()
+        .filter(|interval| passes_filters(query, interval))
+    {
+        grouped
+            .entry(resample.interval.bucket_start(interval.start_time))
+            .or_default()
+            .push(interval);
+    }
+
+    let buckets: Vec<RunepoolUnitsInterval> = grouped
+        .into_iter()
+        .map(|(start_time, members)| {
+            let latest = members
+                .iter()
+                .max_by_key(|member| member.start_time)
+                .unwrap();
+            RunepoolUnitsInterval {
+                start_time,
+                end_time: resample.interval.bucket_end(start_time),
+                count: match resample.count_mode {
+                    CountMode::Last => latest.count,
+                    CountMode::Sum => members.iter().map(|member| member.count).sum(),
+                },
+                units: latest.units,
+            }
+        })
+        .collect();
+
+    // Buckets are only sorted and paged, the filters already ran on the intervals
+    let bucket_query = HistoryQuery {
+        start_time: None,
+        end_time: None,
+        units: ValueRange::default(),
+        count: ValueRange::default(),
+        ..query.clone()
+    };
+    expected(&buckets, &bucket_query)
+}
+
+// One hour intervals about nine days apart through 2001, starting on odd minutes, so
+// every bucket size has boundaries to fall on either side of
+fn spread_intervals() -> Vec<RunepoolUnitsInterval> {
+    let start = utc(2001, 1, 1, 0, 0);
+    (0..42)
+        .map(|i| {
+            let start_time = start + Duration::minutes(i * (211 * 60 + 7));
+            RunepoolUnitsInterval {
+                start_time,
+                end_time: start_time + Duration::hours(1),
+                count: (i % 5) as u64,
+                units: ((i * 13) % 9) as u64 * 10,
+            }
+        })
+        .collect()
+}
+
+fn year_2001() -> (DateTime<Utc>, DateTime<Utc>) {
+    (utc(2001, 1, 1, 0, 0), utc(2002, 1, 1, 0, 0))
+}
+
+async fn check_buckets<S: RunepoolStore>(store: &S, intervals: &[RunepoolUnitsInterval]) {
+    for interval in INTERVALS {
+        for count_mode in [CountMode::Last, CountMode::Sum] {
+            let resample = Resample {
+                interval,
+                count_mode,
+            };
+            for sort_field in [SortField::StartTime, SortField::Units, SortField::Count] {
+                for sort_order in [SortOrder::Asc, SortOrder::Desc] {
+                    let (start, end) = year_2001();
+                    let query = HistoryQuery {
+                        start_time: Some(start),
+                        end_time: Some(end),
+                        ..history_query(sort_field, sort_order)
+                    };
+                    let filtered = HistoryQuery {
+                        units: ValueRange::default().at_least(200),
+                        ..query.clone()
+                    };
+                    let second_page = HistoryQuery {
+                        limit: 3,
+                        offset: 3,
+                        ..query.clone()
+                    };
+
+                    for query in [query, filtered, second_page] {
+                        let results = store.resample(&query, resample).await.unwrap();
+                        assert_eq!(
+                            spans(&results),
+                            spans(&expected_buckets(intervals, &query, resample)),
+                            "{} {:?} {:?}",
+                            S::DATABASE_TYPE.name(),
+                            resample,
+                            query
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn check_resample<S: RunepoolStore>(store: &S) {
+    seed(store).await;
+    check_buckets(store, &sample_intervals()).await;
+    cleanup(store).await;
+
+    let (start, end) = year_2001();
+    let intervals = spread_intervals();
+    store.delete_range(start, end).await.unwrap();
+    store.insert(&intervals).await.unwrap();
+    check_buckets(store, &intervals).await;
+    store.delete_range(start, end).await.unwrap();
+}
+
+backend_tests!(check_resample);
@@ -0,0 +1,192 @@
+// Moving averages on every backend against averages worked out over the whole sample,
+// so every page needs the intervals before it
+mod common;
+
+use common::*;
+use db_tester::core::models::common::{
+    CountMode, HistoryCursor, Interval, SortField, SortOrder, ValueRange,
+};
+use db_tester::core::models::runepool_units_history::SmoothedRunepoolUnitsInterval;
+use db_tester::services::repository::smoothing::{smooth, Smoothing};
+use db_tester::services::repository::{HistoryQuery, Resample, RunepoolStore};
+
+// Each average straight from its definition, no running sums
+fn expected_smoothed(
+    query: &HistoryQuery,
+    smoothing: Smoothing,
+) -> Vec<SmoothedRunepoolUnitsInterval> {
+    let series: Vec<_> = sample_intervals()
+        .into_iter()
+        .filter(|interval| passes_filters(query, interval))
+        .collect();
+
+    let mut smoothed: Vec<SmoothedRunepoolUnitsInterval> = Vec::new();
+    for (i, interval) in series.iter().enumerate() {
+        let units_sma = smoothing.sma.map(|window| {
+            let window = &series[(i + 1).saturating_sub(window as usize)..=i];
+            window
+                .iter()
+                .map(|interval| interval.units as f64)
+                .sum::<f64>()
+                / window.len() as f64
+        });
+        let units_ema = smoothing.ema.map(|window| {
+            let alpha = 2.0 / (window as f64 + 1.0);
+            match smoothed.last() {
+                Some(previous) => {
+                    alpha * interval.units as f64 + (1.0 - alpha) * previous.units_ema.unwrap()
+                }
+                None => interval.units as f64,
+            }
+        });
+        smoothed.push(SmoothedRunepoolUnitsInterval {
+            interval: interval.clone(),
+            units_sma,
+            units_ema,
+        });
+    }
+
+    if query.sort_order == SortOrder::Desc {
+        smoothed.reverse();
+    }
+    smoothed
+        .into_iter()
+        .skip(query.offset as usize)
+        .take(query.limit as usize)
+        .collect()
+}
+
+fn assert_smoothed(
+    actual: &[SmoothedRunepoolUnitsInterval],
+    expected: &[SmoothedRunepoolUnitsInterval],
+    context: &str,
+) {
+    let times = |smoothed: &[SmoothedRunepoolUnitsInterval]| -> Vec<i64> {
+        smoothed
+            .iter()
+            .map(|smoothed| smoothed.interval.start_time.timestamp())
+            .collect()
+    };
+    assert_eq!(times(actual), times(expected), "{}", context);
+    for (actual, expected) in actual.iter().zip(expected) {
+        assert!(
+            close(actual.units_sma, expected.units_sma)
+                && close(actual.units_ema, expected.units_ema),
+            "{} {:?} {:?}",
+            context,
+            actual,
+            expected
+        );
+    }
+}
+
+fn smoothings() -> Vec<Smoothing> {
+    vec![
+        Smoothing {
+            sma: Some(3),
+            ema: None,
+        },
+        Smoothing {
+            sma: None,
+            ema: Some(4),
+        },
+        Smoothing {
+            sma: Some(10),
+            ema: Some(6),
+        },
+        // A window of one is the units themselves
+        Smoothing {
+            sma: Some(1),
+            ema: Some(1),
+        },
+    ]
+}
+
+fn queries() -> Vec<HistoryQuery> {
+    let any = ValueRange::default();
+    let mut queries = Vec::new();
+    for (from, to, units) in [(0, 45, any), (5, 30, any.at_least(200))] {
+        for sort_order in [SortOrder::Asc, SortOrder::Desc] {
+            for (limit, offset) in [(400, 0), (4, 0), (4, 9), (4, 40)] {
+                queries.push(HistoryQuery {
+                    limit,
+                    offset,
+                    start_time: Some(hour(from)),
+                    end_time: Some(hour(to)),
+                    units,
+                    ..history_query(SortField::StartTime, sort_order)
+                });
+            }
+        }
+    }
+    queries
+}
+
+async fn check_smoothing<S: RunepoolStore>(store: &S) {
+    seed(store).await;
+
+    // Hour buckets of the hourly sample are the sample itself, so the resampled
+    // history has the same averages, fetched by offset instead of by cursor
+    let hourly = Resample {
+        interval: Interval::Hour,
+        count_mode: CountMode::Last,
+    };
+    for query in queries() {
+        for smoothing in smoothings() {
+            let expected = expected_smoothed(&query, smoothing);
+            for resample in [None, Some(hourly)] {
+                let page = match resample {
+                    Some(resample) => store.resample(&query, resample).await.unwrap(),
+                    None => store.query(&query).await.unwrap(),
+                };
+                let smoothed = smooth(store, &query, resample, page, smoothing)
+                    .await
+                    .unwrap();
+                assert_smoothed(
+                    &smoothed,
+                    &expected,
+                    &format!(
+                        "{} {:?} {:?} {:?}",
+                        S::DATABASE_TYPE.name(),
+                        smoothing,
+                        resample,
+                        query
+                    ),
+                );
+            }
+        }
+    }
+
+    // Pages reached with a cursor get the same averages as one big page
+    for sort_order in [SortOrder::Asc, SortOrder::Desc] {
+        let smoothing = Smoothing {
+            sma: Some(5),
+            ema: Some(5),
+        };
+        let full = history_query(SortField::StartTime, sort_order);
+        let mut paged = Vec::new();
+        let mut after = None;
+        loop {
+            let query = HistoryQuery {
+                limit: 7,
+                after,
+                ..full.clone()
+            };
+            let page = store.query(&query).await.unwrap();
+            let Some(last) = page.last() else {
+                break;
+            };
+            after = Some(HistoryCursor::after(last, SortField::StartTime, sort_order));
+            paged.extend(smooth(store, &query, None, page, smoothing).await.unwrap());
+        }
+        assert_smoothed(
+            &paged,
+            &expected_smoothed(&full, smoothing),
+            &format!("{} {:?}", S::DATABASE_TYPE.name(), sort_order),
+        );
+    }
+
+    cleanup(store).await;
+}
+
+backend_tests!(check_smoothing);
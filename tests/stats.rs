@@ -0,0 +1,167 @@
+// Stats on every backend against the same numbers worked out in plain rust
+mod common;
+
+use common::*;
+use db_tester::core::models::common::{SortField, SortOrder, ValueRange};
+use db_tester::core::models::runepool_units_history::{FieldStats, RunepoolUnitsStats};
+use db_tester::services::repository::{HistoryQuery, RunepoolStore};
+
+// Two passes, mean first, so the reference doesn't share the stores' running formulas
+fn expected_field(values: &[u64]) -> FieldStats {
+    let n = values.len() as f64;
+    let avg = values.iter().map(|&value| value as f64).sum::<f64>() / n;
+    let stddev = if values.len() > 1 {
+        let squares: f64 = values
+            .iter()
+            .map(|&value| (value as f64 - avg).powi(2))
+            .sum();
+        (squares / (n - 1.0)).sqrt()
+    } else {
+        0.0
+    };
+    FieldStats::new(
+        *values.iter().min().unwrap(),
+        *values.iter().max().unwrap(),
+        avg,
+        stddev,
+        values[0],
+        values[values.len() - 1],
+    )
+}
+
+fn expected_stats(query: &HistoryQuery) -> Option<RunepoolUnitsStats> {
+    let matching: Vec<_> = sample_intervals()
+        .into_iter()
+        .filter(|interval| passes_filters(query, interval))
+        .collect();
+    let (first, last) = (matching.first()?, matching.last()?);
+    let units: Vec<u64> = matching.iter().map(|interval| interval.units).collect();
+    let count: Vec<u64> = matching.iter().map(|interval| interval.count).collect();
+    Some(RunepoolUnitsStats {
+        start_time: first.start_time,
+        end_time: last.end_time,
+        intervals: matching.len() as u64,
+        units: expected_field(&units),
+        count: expected_field(&count),
+    })
+}
+
+fn assert_field(actual: &FieldStats, expected: &FieldStats, context: &str) {
+    assert_eq!(
+        (
+            actual.min,
+            actual.max,
+            actual.first,
+            actual.last,
+            actual.change
+        ),
+        (
+            expected.min,
+            expected.max,
+            expected.first,
+            expected.last,
+            expected.change
+        ),
+        "{}",
+        context
+    );
+    assert!(
+        close(actual.avg, expected.avg),
+        "{} avg {:?}",
+        context,
+        actual
+    );
+    assert!(
+        close(actual.stddev, expected.stddev),
+        "{} stddev {:?} {:?}",
+        context,
+        actual,
+        expected
+    );
+    match (actual.change_pct, expected.change_pct) {
+        (Some(a), Some(b)) => assert!(close(a, b), "{} change_pct {:?}", context, actual),
+        (a, b) => assert_eq!(a, b, "{} change_pct", context),
+    }
+}
+
+fn queries() -> Vec<HistoryQuery> {
+    let any = ValueRange::default();
+    let window = |from: i64, to: i64, units: ValueRange, count: ValueRange| HistoryQuery {
+        start_time: Some(hour(from)),
+        end_time: Some(hour(to)),
+        units,
+        count,
+        ..history_query(SortField::StartTime, SortOrder::Asc)
+    };
+    vec![
+        // Whole sample, the first units are 0 so there is no percentage change
+        window(0, 45, any, any),
+        window(1, 30, any, any),
+        window(0, 45, any.at_least(300), any),
+        window(5, 20, any, any.at_most(1)),
+        // A single interval has no spread
+        window(3, 4, any, any),
+        // Sorting and paging don't change the stats
+        HistoryQuery {
+            limit: 2,
+            offset: 7,
+            sort_field: SortField::Units,
+            sort_order: SortOrder::Desc,
+            ..window(2, 40, any, any)
+        },
+        window(0, 45, any.greater_than(600), any),
+        window(10, 10, any, any),
+    ]
+}
+
+async fn check_stats<S: RunepoolStore>(store: &S) {
+    seed(store).await;
+
+    for query in queries() {
+        let context = format!("{} {:?}", S::DATABASE_TYPE.name(), query);
+        let stats = store.stats(&query).await.unwrap();
+        match (stats, expected_stats(&query)) {
+            (Some(stats), Some(expected)) => {
+                assert_eq!(
+                    (stats.start_time, stats.end_time, stats.intervals),
+                    (expected.start_time, expected.end_time, expected.intervals),
+                    "{}",
+                    context
+                );
+                assert_field(&stats.units, &expected.units, &format!("{} units", context));
+                assert_field(&stats.count, &expected.count, &format!("{} count", context));
+            }
+            (stats, expected) => {
+                assert!(
+                    stats.is_none() && expected.is_none(),
+                    "{} {:?}",
+                    context,
+                    stats
+                );
+            }
+        }
+    }
+
+    cleanup(store).await;
+}
+
+#[test]
+fn change_is_relative_to_the_first_value() {
+    let stats = FieldStats::new(50, 400, 200.0, 10.0, 200, 50);
+    assert_eq!(stats.change, -150);
+    assert_eq!(stats.change_pct, Some(-75.0));
+
+    assert_eq!(FieldStats::new(0, 300, 100.0, 0.0, 0, 300).change_pct, None);
+
+    // Past i64::MAX the change saturates instead of wrapping
+    let rising = FieldStats::new(1, u64::MAX, 0.0, 0.0, 1, u64::MAX);
+    assert_eq!(rising.change, i64::MAX);
+    let falling = FieldStats::new(1, u64::MAX, 0.0, 0.0, u64::MAX, 1);
+    assert_eq!(falling.change, i64::MIN);
+    assert_eq!(falling.change_pct, Some(-100.0));
+
+    let stats = serde_json::to_value(stats).unwrap();
+    assert_eq!(stats["changePct"], -75.0);
+}
+
+backend_tests!(check_stats);
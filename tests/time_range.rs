@@ -0,0 +1,137 @@
+// from/to parsing and the half-open [from, to) window on every backend: an interval
+// is returned when it starts at or after `from` and ends by `to`
+mod common;
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use common::*;
+use db_tester::core::models::common::{SortField, SortOrder, TimeRange};
+use db_tester::services::repository::{HistoryQuery, RunepoolStore};
+use serde_json::json;
+
+fn at(hour: i64, minute: i64) -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(2001, 1, 1, 0, 0, 0).unwrap()
+        + Duration::hours(hour)
+        + Duration::minutes(minute)
+}
+
+#[test]
+fn parses_every_time_format() {
+    let expected = Some(Utc.with_ymd_and_hms(2001, 1, 2, 3, 4, 5).unwrap());
+    for from in [
+        "2001-01-02T03:04:05Z",
+        "2001-01-02T05:04:05+02:00",
+        "978404645",
+    ] {
+        let (parsed, to) = params(json!({ "from": from })).parse_time_range().unwrap();
+        assert_eq!((parsed, to), (expected, None), "{}", from);
+    }
+
+    let (from, to) = params(json!({ "to": "2001-01-02" }))
+        .parse_time_range()
+        .unwrap();
+    assert_eq!(from, None);
+    assert_eq!(to, Some(Utc.with_ymd_and_hms(2001, 1, 2, 0, 0, 0).unwrap()));
+}
+
+#[test]
+fn date_range_covers_both_days() {
+    let (from, to) = params(json!({ "date_range": "2001-01-01,2001-01-02" }))
+        .parse_time_range()
+        .unwrap();
+    assert_eq!(
+        from,
+        Some(Utc.with_ymd_and_hms(2001, 1, 1, 0, 0, 0).unwrap())
+    );
+    assert_eq!(to, Some(Utc.with_ymd_and_hms(2001, 1, 3, 0, 0, 0).unwrap()));
+}
+
+#[test]
+fn rejects_bad_time_ranges() {
+    for value in [
+        json!({ "from": "yesterday" }),
+        // Years and compact dates aren't taken as unix seconds
+        json!({ "from": "2024" }),
+        json!({ "to": "20240102" }),
+        json!({ "from": "0" }),
+        json!({ "to": "2001-13-01" }),
+        json!({ "from": "2001-01-02", "to": "2001-01-01" }),
+        json!({ "date_range": "2001-01-01" }),
+        json!({ "date_range": "2001-01-01,2001-01-02", "from": "2001-01-01" }),
+    ] {
+        assert!(
+            params(value.clone()).parse_time_range().is_err(),
+            "{}",
+            value
+        );
+    }
+}
+
+// (from, to) windows with ends on, between and outside interval boundaries
+fn windows() -> Vec<TimeRange> {
+    vec![
+        (None, None),
+        (Some(at(10, 0)), None),
+        (Some(at(10, 30)), None),
+        (None, Some(at(20, 0))),
+        (None, Some(at(20, 30))),
+        (Some(at(5, 0)), Some(at(6, 0))),
+        (Some(at(5, 0)), Some(at(5, 59))),
+        (Some(at(5, 15)), Some(at(30, 45))),
+        (Some(at(-10, 0)), Some(at(100, 0))),
+        (Some(at(100, 0)), None),
+    ]
+}
+
+async fn check_windows<S: RunepoolStore>(store: &S) {
+    seed(store).await;
+    let intervals = sample_intervals();
+    let (window_start, window_end) = sample_window();
+
+    for (start_time, end_time) in windows() {
+        for sort_order in [SortOrder::Asc, SortOrder::Desc] {
+            let query = HistoryQuery {
+                start_time,
+                end_time,
+                ..history_query(SortField::StartTime, sort_order)
+            };
+
+            let mut inside: Vec<_> = intervals
+                .iter()
+                .filter(|interval| {
+                    start_time.is_none_or(|from| interval.start_time >= from)
+                        && end_time.is_none_or(|to| interval.end_time <= to)
+                })
+                .cloned()
+                .collect();
+            if sort_order == SortOrder::Desc {
+                inside.reverse();
+            }
+
+            // An open end reaches past the sample window, into whatever else the
+            // database holds, so only the sample intervals are compared
+            let results: Vec<_> = store
+                .query(&query)
+                .await
+                .unwrap()
+                .into_iter()
+                .filter(|interval| {
+                    interval.start_time >= window_start && interval.end_time <= window_end
+                })
+                .collect();
+            assert_eq!(
+                spans(&results),
+                spans(&inside),
+                "{} {:?}",
+                S::DATABASE_TYPE.name(),
+                query
+            );
+            if start_time.is_some() && end_time.is_some() {
+                assert_eq!(store.count(&query).await.unwrap(), inside.len() as u64);
+            }
+        }
+    }
+
+    cleanup(store).await;
+}
+
+backend_tests!(check_windows);